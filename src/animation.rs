@@ -0,0 +1,140 @@
+use std::f64::consts::PI;
+use std::time::Duration;
+
+/// Durations of animations, zero disables an animation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Durations {
+    /// Fading out of removed tiles and fading in of tiles put back by undo
+    pub fade: Duration,
+    /// Shaking of a tile that cannot be selected
+    pub shake: Duration,
+}
+
+impl Durations {
+    pub fn disabled() -> Self {
+        Self {
+            fade: Duration::ZERO,
+            shake: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for Durations {
+    fn default() -> Self {
+        Self {
+            fade: Duration::from_millis(250),
+            shake: Duration::from_millis(300),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Kind {
+    FadeOut,
+    FadeIn,
+    Shake,
+}
+
+/// How an animated tile is drawn
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Effect {
+    pub alpha: f64,
+    /// Offset from the tile position in tile sizes
+    pub offset: [f64; 2],
+}
+
+impl Default for Effect {
+    fn default() -> Self {
+        Self {
+            alpha: 1.0,
+            offset: [0.0, 0.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Animation {
+    index: usize,
+    kind: Kind,
+    /// Seconds since the start
+    time: f64,
+    duration: f64,
+}
+
+impl Animation {
+    fn effect(&self) -> Effect {
+        let t = (self.time / self.duration).min(1.0);
+        // ease out
+        let eased = 1.0 - (1.0 - t) * (1.0 - t);
+
+        match self.kind {
+            Kind::FadeOut => Effect {
+                alpha: 1.0 - eased,
+                offset: [0.0, -0.3 * eased],
+            },
+            Kind::FadeIn => Effect {
+                alpha: eased,
+                offset: [0.0, -0.3 * (1.0 - eased)],
+            },
+            Kind::Shake => Effect {
+                alpha: 1.0,
+                offset: [(t * 6.0 * PI).sin() * (1.0 - t) * 0.1, 0.0],
+            },
+        }
+    }
+}
+
+/// Running tile animations, driven by update events
+#[derive(Debug, Clone)]
+pub struct Animations {
+    durations: Durations,
+    running: Vec<Animation>,
+}
+
+impl Animations {
+    pub fn new(durations: Durations) -> Self {
+        Self {
+            durations,
+            running: Vec::new(),
+        }
+    }
+
+    /// Starts animating a tile, replacing its running animation
+    pub fn start(&mut self, index: usize, kind: Kind) {
+        let duration = match kind {
+            Kind::FadeOut | Kind::FadeIn => self.durations.fade,
+            Kind::Shake => self.durations.shake,
+        };
+
+        self.running.retain(|animation| animation.index != index);
+        if duration > Duration::ZERO {
+            self.running.push(Animation {
+                index,
+                kind,
+                time: 0.0,
+                duration: duration.as_secs_f64(),
+            });
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.running.clear();
+    }
+
+    /// Advances animations by `dt` seconds, dropping finished ones
+    pub fn update(&mut self, dt: f64) {
+        for animation in &mut self.running {
+            animation.time += dt;
+        }
+        self.running
+            .retain(|animation| animation.time < animation.duration);
+    }
+
+    /// Returns the effect of a running animation of the tile, if any
+    pub fn effect(&self, index: usize) -> Option<Effect> {
+        self.running
+            .iter()
+            .find(|animation| animation.index == index)
+            .map(Animation::effect)
+    }
+}
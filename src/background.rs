@@ -0,0 +1,231 @@
+use crate::tileset;
+use anyhow::{anyhow, Result};
+use piston_window::*;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// How a background image covers the window
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Placement {
+    Stretch,
+    Tile,
+    Center,
+}
+
+impl Placement {
+    pub fn next(self) -> Self {
+        match self {
+            Placement::Stretch => Placement::Tile,
+            Placement::Tile => Placement::Center,
+            Placement::Center => Placement::Stretch,
+        }
+    }
+}
+
+impl FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "stretch" => Ok(Placement::Stretch),
+            "tile" => Ok(Placement::Tile),
+            "center" => Ok(Placement::Center),
+            _ => Err(anyhow!("Unknown placement: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Placement::Stretch => write!(f, "stretch"),
+            Placement::Tile => write!(f, "tile"),
+            Placement::Center => write!(f, "center"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Background {
+    pub name: String,
+    texture: G2dTexture,
+}
+
+impl Background {
+    pub fn draw(&self, placement: Placement, size: Size, c: &Context, g: &mut G2d) {
+        let (width, height) = self.texture.get_size();
+        let (width, height) = (width as f64, height as f64);
+
+        match placement {
+            Placement::Stretch => {
+                Image::new().rect([0.0, 0.0, size.width, size.height]).draw(
+                    &self.texture,
+                    &c.draw_state,
+                    c.transform,
+                    g,
+                );
+            }
+            Placement::Tile => {
+                let mut y = 0.0;
+                while y < size.height {
+                    let mut x = 0.0;
+                    while x < size.width {
+                        Image::new().draw(&self.texture, &c.draw_state, c.transform.trans(x, y), g);
+                        x += width;
+                    }
+                    y += height;
+                }
+            }
+            Placement::Center => {
+                Image::new().draw(
+                    &self.texture,
+                    &c.draw_state,
+                    c.transform
+                        .trans((size.width - width) / 2.0, (size.height - height) / 2.0),
+                    g,
+                );
+            }
+        }
+    }
+}
+
+/// Background images to choose from and the current choice
+#[derive(Clone)]
+pub struct Backgrounds {
+    pub images: Vec<Background>,
+    /// Index of the shown image, None for the plain background color
+    pub current: Option<usize>,
+    pub placement: Placement,
+}
+
+impl Backgrounds {
+    pub fn current(&self) -> Option<&Background> {
+        self.current.map(|current| &self.images[current])
+    }
+
+    /// Cycles through the images, with the plain color after the last one
+    pub fn select_next(&mut self) {
+        self.current = match self.current {
+            None if !self.images.is_empty() => Some(0),
+            Some(current) if current + 1 < self.images.len() => Some(current + 1),
+            _ => None,
+        };
+    }
+}
+
+impl Default for Backgrounds {
+    fn default() -> Self {
+        Self {
+            images: Vec::new(),
+            current: None,
+            placement: Placement::Stretch,
+        }
+    }
+}
+
+/// Loads background images from files and directories, skipping those that fail to load
+pub fn load_from_paths<P: AsRef<Path>>(window: &mut PistonWindow, paths: &[P]) -> Vec<Background> {
+    let mut backgrounds = Vec::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        if path.is_file() {
+            match load(window, path) {
+                Ok(Some(background)) => backgrounds.push(background),
+                Ok(None) => (),
+                Err(err) => eprintln!("Failed to load {}: {}", path.display(), err),
+            }
+        } else if let Ok(rd) = path.read_dir() {
+            let mut entries: Vec<_> = rd.flatten().map(|entry| entry.path()).collect();
+            entries.sort();
+
+            // images of KMahjongg backgrounds are next to their .desktop files
+            let is_desktop =
+                |path: &Path| path.extension().and_then(|ext| ext.to_str()) == Some("desktop");
+            if entries.iter().any(|entry| is_desktop(entry)) {
+                entries.retain(|entry| is_desktop(entry));
+            }
+            for entry in entries {
+                if let Ok(Some(background)) = load(window, &entry) {
+                    backgrounds.push(background);
+                }
+            }
+        }
+    }
+
+    backgrounds
+}
+
+/// Loads a KMahjongg background (.desktop) or an image file
+///
+/// Returns None for KMahjongg backgrounds that are plain colors.
+pub fn load<P: AsRef<Path>>(window: &mut PistonWindow, path: P) -> Result<Option<Background>> {
+    let path = path.as_ref();
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    if ext.as_deref() == Some("desktop") {
+        return load_kmahjongg(window, path);
+    }
+
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    load_image(window, path, name).map(Some)
+}
+
+fn load_kmahjongg(window: &mut PistonWindow, path: &Path) -> Result<Option<Background>> {
+    const BACKGROUND_VERSION_FORMAT: i32 = 1;
+
+    let desktop = ini::Ini::load_from_file(path)?;
+    let section = desktop
+        .section(Some("KMahjonggBackground"))
+        .ok_or_else(|| anyhow!("Invalid background format"))?;
+
+    if let Some(version) = section.get("VersionFormat") {
+        if version.parse::<i32>().unwrap_or(0) > BACKGROUND_VERSION_FORMAT {
+            return Err(anyhow!("Unsupported version"));
+        }
+    }
+
+    if section.get("Plain").map(|plain| plain != "0") == Some(true) {
+        return Ok(None);
+    }
+
+    let name = section
+        .get("Name")
+        .ok_or_else(|| anyhow!("Invalid background: no name"))?
+        .to_string();
+    let filename = section
+        .get("FileName")
+        .ok_or_else(|| anyhow!("Invalid background: no image filename"))?;
+    // safe to unwrap, or reading desktop file would have failed
+    let filename = path.parent().unwrap().join(filename);
+
+    load_image(window, &filename, name).map(Some)
+}
+
+fn load_image(window: &mut PistonWindow, path: &Path, name: String) -> Result<Background> {
+    let texture = if let Ok(buf) = tileset::render_svg(path) {
+        Texture::from_image(
+            &mut window.create_texture_context(),
+            &buf,
+            &TextureSettings::new(),
+        )
+        .map_err(|_| anyhow!("Failed to load image"))?
+    } else {
+        Texture::from_path(
+            &mut window.create_texture_context(),
+            path,
+            Flip::None,
+            &TextureSettings::new(),
+        )
+        .map_err(|_| anyhow!("Failed to load image"))?
+    };
+
+    Ok(Background { name, texture })
+}
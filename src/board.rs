@@ -0,0 +1,667 @@
+use crate::dealer::{Dealer, Difficulty};
+use crate::exposure::Exposure;
+use crate::map::{Map, Slot};
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Search steps of each random attempt at shuffling, small enough not to stall the game
+const SHUFFLE_MAX_STEPS: usize = 10_000;
+/// Random attempts at shuffling started over before falling back to a systematic search
+const SHUFFLE_RESTARTS: usize = 2;
+/// Search steps of the systematic fallback of shuffling
+const SHUFFLE_FALLBACK_STEPS: usize = 50_000;
+
+/// Game state independent of any frontend
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawBoard")]
+pub struct Board {
+    map: Map,
+    seed: u64,
+    #[serde(default)]
+    difficulty: Difficulty,
+    tiles: Vec<Tile>,
+    selected: Option<usize>,
+    history: Vec<Move>,
+    /// Undone moves, most recent last
+    redo_stack: Vec<Move>,
+    /// Used for shuffling
+    rng: ChaCha8Rng,
+    /// Rebuilt from the tiles after loading
+    #[serde(skip)]
+    exposure: Exposure,
+}
+
+/// Board as stored, checked before a `Board` is made of it
+#[derive(Deserialize)]
+struct RawBoard {
+    map: Map,
+    seed: u64,
+    #[serde(default)]
+    difficulty: Difficulty,
+    tiles: Vec<Tile>,
+    selected: Option<usize>,
+    history: Vec<Move>,
+    redo_stack: Vec<Move>,
+    rng: ChaCha8Rng,
+}
+
+impl TryFrom<RawBoard> for Board {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawBoard) -> Result<Self> {
+        let mut board = Self {
+            map: raw.map,
+            seed: raw.seed,
+            difficulty: raw.difficulty,
+            tiles: raw.tiles,
+            selected: raw.selected,
+            history: raw.history,
+            redo_stack: raw.redo_stack,
+            rng: raw.rng,
+            exposure: Exposure::default(),
+        };
+        board.validate()?;
+        Ok(board)
+    }
+}
+
+impl Board {
+    /// Deals a random solvable configuration on the given map
+    ///
+    /// The same map and seed always yield the same deal.
+    pub fn new(map: Map, seed: u64) -> Result<Self> {
+        Dealer::new().deal(map, seed, &mut |_| ())
+    }
+
+    pub(crate) fn from_deal(
+        map: Map,
+        seed: u64,
+        difficulty: Difficulty,
+        tiles: Vec<Tile>,
+        rng: ChaCha8Rng,
+        exposure: Exposure,
+    ) -> Self {
+        Self {
+            map,
+            seed,
+            difficulty,
+            tiles,
+            selected: None,
+            history: Vec::new(),
+            redo_stack: Vec::new(),
+            rng,
+            exposure,
+        }
+    }
+
+    pub fn map(&self) -> &Map {
+        &self.map
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    /// Tiles in draw order
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+
+    pub fn selected(&self) -> Option<usize> {
+        self.selected
+    }
+
+    pub fn history(&self) -> &[Move] {
+        &self.history
+    }
+
+    /// Moves that can be redone, next one last
+    pub fn redo_stack(&self) -> &[Move] {
+        &self.redo_stack
+    }
+
+    pub fn is_exposed(&self, index: usize) -> bool {
+        self.exposure.is_exposed(index, &self.tiles)
+    }
+
+    /// Returns all pairs of exposed matching tiles
+    pub fn find_all_matches(&self) -> Vec<Match> {
+        find_all_matches(&self.tiles, &self.exposure)
+    }
+
+    pub(crate) fn exposure(&self) -> &Exposure {
+        &self.exposure
+    }
+
+    /// Selects a tile, removing it together with the previously selected one if they match
+    ///
+    /// Returns the removed pair, if any.
+    pub fn select(&mut self, index: usize) -> Option<Match> {
+        if !self.is_exposed(index) {
+            // tile is not removable
+            return None;
+        }
+
+        let prev = if let Some(prev) = self.selected {
+            prev
+        } else {
+            // select first of pair
+            self.selected = Some(index);
+            return None;
+        };
+
+        if index == prev {
+            // cancel selection
+            self.selected = None;
+            return None;
+        }
+
+        if self.tiles[index].matches(&self.tiles[prev]) {
+            let m = Match(prev, index);
+            self.remove(m);
+            return Some(m);
+        }
+
+        // select another tile
+        self.selected = Some(index);
+        None
+    }
+
+    pub fn deselect(&mut self) {
+        self.selected = None;
+    }
+
+    /// Returns true if the pair consists of two distinct exposed matching tiles
+    pub fn can_remove(&self, m: Match) -> bool {
+        m.0 != m.1
+            && m.0 < self.tiles.len()
+            && m.1 < self.tiles.len()
+            && self.tiles[m.0].matches(&self.tiles[m.1])
+            && self.is_exposed(m.0)
+            && self.is_exposed(m.1)
+    }
+
+    /// Removes a pair of exposed matching tiles
+    pub fn remove(&mut self, m: Match) {
+        debug_assert!(self.tiles[m.0].matches(&self.tiles[m.1]));
+        debug_assert!(self.is_exposed(m.0) && self.is_exposed(m.1));
+
+        self.push_move(Move::Remove(m));
+    }
+
+    /// Rearranges remaining tiles into a solvable configuration
+    ///
+    /// Fails if none is found within a search budget small enough to run between frames.
+    pub fn shuffle(&mut self) -> Result<()> {
+        if self.is_cleared() {
+            return Err(anyhow!("No tiles left"));
+        }
+
+        let before: Vec<_> = self.tiles.iter().map(|tile| tile.id).collect();
+
+        let mut ids: Vec<_> = self
+            .tiles
+            .iter()
+            .filter(|tile| tile.visible)
+            .map(|tile| tile.id)
+            .collect();
+        // with an even number of tiles left of every kind,
+        // adjacent ids in sorted order form matching pairs
+        ids.sort_unstable();
+        let mut pairs = ids
+            .chunks(2)
+            .map(|pair| match *pair {
+                [a, b] if a / 4 == b / 4 => Ok((a, b)),
+                _ => Err(anyhow!("Remaining tiles do not form pairs")),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        pairs.shuffle(&mut self.rng);
+
+        for tile in self.tiles.iter_mut().filter(|tile| tile.visible) {
+            // any two of the remaining tiles can be paired
+            tile.id = 0;
+        }
+        let filled = Dealer::new()
+            .max_steps(SHUFFLE_MAX_STEPS)
+            .restarts(SHUFFLE_RESTARTS)
+            .fallback_steps(SHUFFLE_FALLBACK_STEPS)
+            .fill(
+                &mut self.tiles,
+                &mut self.exposure,
+                &pairs,
+                &mut self.rng,
+                &mut |_| (),
+            );
+
+        if let Err(err) = filled {
+            self.set_ids(&before);
+            return Err(err);
+        }
+
+        let after = self.tiles.iter().map(|tile| tile.id).collect();
+        self.push_move(Move::Shuffle { before, after });
+
+        Ok(())
+    }
+
+    /// Rearranges tiles into the given ids, as recorded from an earlier shuffle
+    pub(crate) fn shuffle_to(&mut self, after: Vec<usize>) -> Result<()> {
+        let before: Vec<_> = self.tiles.iter().map(|tile| tile.id).collect();
+
+        // shuffling only permutes ids
+        let mut sorted_before = before.clone();
+        let mut sorted_after = after.clone();
+        sorted_before.sort_unstable();
+        sorted_after.sort_unstable();
+        if sorted_before != sorted_after {
+            return Err(anyhow!("Invalid shuffle"));
+        }
+
+        self.push_move(Move::Shuffle { before, after });
+        Ok(())
+    }
+
+    /// Reverts the last move
+    ///
+    /// Returns the reverted move, if any.
+    pub fn undo(&mut self) -> Option<&Move> {
+        let last = self.history.pop()?;
+        match &last {
+            Move::Remove(m) => {
+                self.exposure.set_visible(&mut self.tiles, m.0, true);
+                self.exposure.set_visible(&mut self.tiles, m.1, true);
+            }
+            Move::Shuffle { before, .. } => self.set_ids(before),
+        }
+        self.redo_stack.push(last);
+
+        self.selected = None;
+        self.redo_stack.last()
+    }
+
+    /// Replays the last undone move
+    ///
+    /// Returns the replayed move, if any.
+    pub fn redo(&mut self) -> Option<&Move> {
+        let next = self.redo_stack.pop()?;
+        self.apply(&next);
+        self.history.push(next);
+
+        self.selected = None;
+        self.history.last()
+    }
+
+    /// Reverts all moves
+    ///
+    /// Returns true if any move was reverted.
+    pub fn undo_all(&mut self) -> bool {
+        let mut changed = false;
+        while self.undo().is_some() {
+            changed = true;
+        }
+        changed
+    }
+
+    /// Replays all undone moves
+    ///
+    /// Returns true if any move was replayed.
+    pub fn redo_all(&mut self) -> bool {
+        let mut changed = false;
+        while self.redo().is_some() {
+            changed = true;
+        }
+        changed
+    }
+
+    /// Checks that the board could have come from play, as boards may come from untrusted files
+    ///
+    /// Also rebuilds state that is not saved.
+    fn validate(&mut self) -> Result<()> {
+        let tiles = &self.tiles;
+        let len = tiles.len();
+        // tiles removed by moves in history are hidden, and those of undone moves are not
+        let valid_move = |m: &Move, removed: bool| match m {
+            Move::Remove(m) => {
+                m.0 < len
+                    && m.1 < len
+                    && m.0 != m.1
+                    && tiles[m.0].visible != removed
+                    && tiles[m.1].visible != removed
+            }
+            Move::Shuffle { before, after } => {
+                // a shuffle only rearranges the tiles
+                let mut sorted_before = before.clone();
+                let mut sorted_after = after.clone();
+                sorted_before.sort_unstable();
+                sorted_after.sort_unstable();
+
+                before.len() == len
+                    && before.iter().all(|&id| id < 144)
+                    && sorted_before == sorted_after
+            }
+        };
+
+        let removes = self
+            .history
+            .iter()
+            .filter(|m| matches!(m, Move::Remove(_)))
+            .count();
+        let hidden = tiles.iter().filter(|tile| !tile.visible).count();
+
+        // tiles are removed in pairs of the same kind,
+        // though a layout of an odd number of slots leaves one tile without a match
+        let mut kinds = [0; 36];
+        for tile in tiles.iter().filter(|tile| tile.visible && tile.id < 144) {
+            kinds[tile.id / 4] += 1;
+        }
+        let unpaired = kinds.iter().filter(|&&count| count % 2 == 1).count();
+
+        if len == self.map.slots.len()
+            && tiles.iter().all(|tile| tile.id < 144)
+            && unpaired <= len % 2
+            && hidden == 2 * removes
+            && self.selected.map(|i| i < len).unwrap_or(true)
+            && self.history.iter().all(|m| valid_move(m, true))
+            && self.redo_stack.iter().all(|m| valid_move(m, false))
+        {
+            self.exposure = Exposure::from_tiles(&self.tiles);
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid board"))
+        }
+    }
+
+    /// Performs a new move, which invalidates undone moves
+    fn push_move(&mut self, m: Move) {
+        self.apply(&m);
+        self.history.push(m);
+        self.redo_stack.clear();
+
+        self.selected = None;
+    }
+
+    fn apply(&mut self, m: &Move) {
+        match m {
+            Move::Remove(m) => {
+                self.exposure.set_visible(&mut self.tiles, m.0, false);
+                self.exposure.set_visible(&mut self.tiles, m.1, false);
+            }
+            Move::Shuffle { after, .. } => self.set_ids(after),
+        }
+    }
+
+    fn set_ids(&mut self, ids: &[usize]) {
+        for (tile, id) in self.tiles.iter_mut().zip(ids) {
+            tile.id = *id;
+        }
+    }
+
+    pub fn state(&self) -> State {
+        if self.is_cleared() {
+            State::Won
+        } else if self.is_stuck() {
+            State::Stuck
+        } else {
+            State::Playing
+        }
+    }
+
+    /// Returns true if all tiles have been removed
+    pub fn is_cleared(&self) -> bool {
+        self.tiles.iter().all(|tile| !tile.visible)
+    }
+
+    /// Returns true if tiles remain but none of them can be removed
+    pub fn is_stuck(&self) -> bool {
+        !self.is_cleared() && self.find_all_matches().is_empty()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum State {
+    Playing,
+    /// All tiles have been removed
+    Won,
+    /// Tiles remain but none of them can be removed
+    Stuck,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Move {
+    Remove(Match),
+    /// Ids of all tiles before and after shuffling
+    Shuffle {
+        before: Vec<usize>,
+        after: Vec<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tile {
+    pub id: usize,
+    pub slot: Slot,
+    pub visible: bool,
+}
+
+impl Tile {
+    pub fn matches(&self, other: &Tile) -> bool {
+        self.id / 4 == other.id / 4
+    }
+}
+
+/// Pair of tile indices
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Match(pub usize, pub usize);
+
+impl PartialEq for Match {
+    fn eq(&self, other: &Match) -> bool {
+        (self.0 == other.0 && self.1 == other.1) || (self.0 == other.1 && self.1 == other.0)
+    }
+}
+
+impl Eq for Match {}
+
+impl std::hash::Hash for Match {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        if self.0 < self.1 {
+            self.0.hash(state);
+            self.1.hash(state);
+        } else {
+            self.1.hash(state);
+            self.0.hash(state);
+        }
+    }
+}
+
+pub(crate) fn find_all_matches(tiles: &[Tile], exposure: &Exposure) -> Vec<Match> {
+    // the order has to be deterministic for seeded deals to be reproducible,
+    // so pairs are ordered by their second tile, then by their first one
+    let exposed = exposure.exposed(tiles);
+    let mut matches = Vec::new();
+    for (j, &b) in exposed.iter().enumerate() {
+        for &a in &exposed[..j] {
+            if tiles[a].matches(&tiles[b]) {
+                matches.push(Match(a, b));
+            }
+        }
+    }
+    matches
+}
+
+/// Returns a bitset of visible tiles, identifying positions on the same map
+pub(crate) fn visible_key(tiles: &[Tile]) -> Vec<u64> {
+    let mut key = vec![0; tiles.len().div_ceil(64)];
+    for (i, tile) in tiles.iter().enumerate() {
+        if tile.visible {
+            key[i / 64] |= 1 << (i % 64);
+        }
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map;
+    use crate::solver::{Solution, Solver};
+
+    /// Checks exposure against the rule applied to every tile from scratch
+    fn assert_exposure(board: &Board) {
+        let tiles = board.tiles();
+        for (i, tile) in tiles.iter().enumerate() {
+            let slot = &tile.slot;
+            let visible_near = |other: &Tile| other.visible && (other.slot.y - slot.y).abs() <= 1;
+            let covered = tiles.iter().any(|other| {
+                visible_near(other)
+                    && other.slot.z == slot.z + 1
+                    && (other.slot.x - slot.x).abs() <= 1
+            });
+            let beside = |dx: isize| {
+                tiles.iter().any(|other| {
+                    visible_near(other) && other.slot.z == slot.z && other.slot.x == slot.x + dx
+                })
+            };
+            let exposed = tile.visible && !covered && !(beside(-2) && beside(2));
+
+            assert_eq!(board.is_exposed(i), exposed, "tile {}", i);
+        }
+    }
+
+    #[test]
+    fn exposure_follows_moves() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        assert_exposure(&board);
+
+        for _ in 0..20 {
+            let m = board.find_all_matches()[0];
+            board.remove(m);
+            assert_exposure(&board);
+        }
+        while board.undo().is_some() {
+            assert_exposure(&board);
+        }
+        while board.redo().is_some() {
+            assert_exposure(&board);
+        }
+    }
+
+    #[test]
+    fn deserialized_board_has_exposure() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        let m = board.find_all_matches()[0];
+        board.remove(m);
+
+        let json = serde_json::to_string(&board).unwrap();
+        let loaded: Board = serde_json::from_str(&json).unwrap();
+        assert_exposure(&loaded);
+        assert_eq!(loaded.find_all_matches(), board.find_all_matches());
+    }
+
+    #[test]
+    fn deserializing_rejects_invalid_shuffles() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        let ids: Vec<_> = board.tiles().iter().map(|tile| tile.id).collect();
+        board.history.push(Move::Shuffle {
+            before: ids.clone(),
+            after: ids.clone(),
+        });
+        let json = serde_json::to_string(&board).unwrap();
+        assert!(serde_json::from_str::<Board>(&json).is_ok());
+
+        let mut changed = ids.clone();
+        changed[0] = changed[1];
+        let mut out_of_range = ids.clone();
+        out_of_range[0] = 144;
+        // ids not rearranged, and ids out of range even if rearranged
+        for (before, after) in [(ids, changed), (out_of_range.clone(), out_of_range)] {
+            board.history[0] = Move::Shuffle { before, after };
+            let json = serde_json::to_string(&board).unwrap();
+            assert!(serde_json::from_str::<Board>(&json).is_err());
+        }
+    }
+
+    fn ids(board: &Board) -> Vec<usize> {
+        board.tiles().iter().map(|tile| tile.id).collect()
+    }
+
+    #[test]
+    fn shuffle_is_solvable_and_undone() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        for _ in 0..10 {
+            let m = board.find_all_matches()[0];
+            board.remove(m);
+        }
+        let before = ids(&board);
+
+        board.shuffle().unwrap();
+        assert_ne!(ids(&board), before);
+        assert!(matches!(Solver::new().solve(&board), Solution::Solved(_)));
+
+        board.undo();
+        assert_eq!(ids(&board), before);
+        assert_exposure(&board);
+    }
+
+    #[test]
+    fn shuffle_refuses_boards_without_pairs() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        if let Solution::Solved(moves) = Solver::new().solve(&board) {
+            for m in moves {
+                board.remove(m);
+            }
+        }
+        assert!(board.is_cleared());
+        let history = board.history().len();
+        assert!(board.shuffle().is_err());
+        assert_eq!(board.history().len(), history);
+
+        let slot = |x| Slot { x, y: 0, z: 0 };
+        let map = Map {
+            name: "Odd".to_string(),
+            slots: vec![slot(0), slot(2), slot(4)],
+            width: 6,
+            height: 2,
+            source: None,
+        };
+        let mut board = Board::new(map, 5).unwrap();
+        assert!(board.shuffle().is_err());
+    }
+
+    #[test]
+    fn deserializing_rejects_impossible_moves() {
+        let mut board = Board::new(map::default::EASY.clone(), 5).unwrap();
+        let m = board.find_all_matches()[0];
+        board.remove(m);
+        let load =
+            |board: &Board| serde_json::from_str::<Board>(&serde_json::to_string(board).unwrap());
+        assert!(load(&board).is_ok());
+
+        let mut same_tile = board.clone();
+        same_tile.history[0] = Move::Remove(Match(m.0, m.0));
+        assert!(load(&same_tile).is_err());
+
+        let mut visible = board.clone();
+        visible
+            .history
+            .push(Move::Remove(board.find_all_matches()[0]));
+        assert!(load(&visible).is_err());
+
+        let mut hidden = board.clone();
+        hidden.redo_stack.push(Move::Remove(m));
+        assert!(load(&hidden).is_err());
+
+        let mut uneven = board.clone();
+        let other = (0..uneven.tiles.len())
+            .find(|&i| uneven.tiles[i].visible && !uneven.tiles[i].matches(&uneven.tiles[0]))
+            .unwrap();
+        uneven.tiles[other].id = uneven.tiles[0].id;
+        assert!(load(&uneven).is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::{Duration, Instant};
+
+/// Stopwatch measuring play time
+#[derive(Debug, Clone)]
+pub struct Clock {
+    /// Time accumulated before `started_at`
+    elapsed_before: Duration,
+    /// None if paused
+    started_at: Option<Instant>,
+}
+
+impl Clock {
+    /// Starts a clock that has already been running for the given duration
+    pub fn start(elapsed: Duration) -> Self {
+        Self {
+            elapsed_before: elapsed,
+            started_at: Some(Instant::now()),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed_before
+            + self
+                .started_at
+                .map(|started_at| started_at.elapsed())
+                .unwrap_or_default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.started_at.is_none()
+    }
+
+    pub fn pause(&mut self) {
+        if let Some(started_at) = self.started_at.take() {
+            self.elapsed_before += started_at.elapsed();
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.started_at.is_none() {
+            self.started_at = Some(Instant::now());
+        }
+    }
+}
+
+/// Formats duration as `m:ss`, or `h:mm:ss` if it is an hour or longer
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -0,0 +1,469 @@
+use crate::board::{self, Board, Match, Tile};
+use crate::exposure::Exposure;
+use crate::map::Map;
+use anyhow::{anyhow, Result};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Number of steps between progress reports
+const PROGRESS_INTERVAL: usize = 4096;
+
+/// How hard deals are to clear
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Difficulty {
+    /// Many moves are open at a time and tiles of a kind are not stacked on each other
+    Easy,
+    /// Moves are chosen uniformly
+    #[default]
+    Medium,
+    /// Few moves are open at a time and tiles of a kind are stacked on each other
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Medium, Difficulty::Hard];
+}
+
+impl FromStr for Difficulty {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "easy" => Ok(Difficulty::Easy),
+            "medium" => Ok(Difficulty::Medium),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(anyhow!("Unknown difficulty: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Difficulty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difficulty::Easy => write!(f, "easy"),
+            Difficulty::Medium => write!(f, "medium"),
+            Difficulty::Hard => write!(f, "hard"),
+        }
+    }
+}
+
+/// Way of searching for a deal
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Places pairs on random matches, backtracking on dead ends
+    Random,
+    /// Places pairs on upper layers first, remembering positions known to be dead ends
+    Systematic,
+}
+
+/// State of a deal in progress
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Progress {
+    pub strategy: Strategy,
+    /// Number of the current attempt, starting from 1
+    pub attempt: usize,
+    /// Positions visited in the current attempt
+    pub steps: usize,
+    /// Most pairs placed at once in the current attempt
+    pub placed: usize,
+    /// Number of pairs to place
+    pub pairs: usize,
+}
+
+/// Generator of random solvable deals with a search budget
+///
+/// Random attempts are made first, each restarting from scratch once it runs out of steps.
+/// If none succeeds, a systematic search is made as a fallback.
+/// The same map, seed, difficulty and budget always yield the same deal.
+#[derive(Debug, Clone)]
+pub struct Dealer {
+    difficulty: Difficulty,
+    max_steps: usize,
+    restarts: usize,
+    fallback_steps: usize,
+    cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for Dealer {
+    fn default() -> Self {
+        Self {
+            difficulty: Difficulty::default(),
+            max_steps: 100_000,
+            restarts: 4,
+            fallback_steps: 1_000_000,
+            cancel: None,
+        }
+    }
+}
+
+impl Dealer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn difficulty(mut self, difficulty: Difficulty) -> Self {
+        self.difficulty = difficulty;
+        self
+    }
+
+    /// Limits the number of positions visited in each random attempt
+    pub fn max_steps(mut self, max_steps: usize) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Sets how many times a random attempt is started over before falling back
+    pub fn restarts(mut self, restarts: usize) -> Self {
+        self.restarts = restarts;
+        self
+    }
+
+    /// Limits the number of positions visited by the fallback search
+    pub fn fallback_steps(mut self, fallback_steps: usize) -> Self {
+        self.fallback_steps = fallback_steps;
+        self
+    }
+
+    /// Stops dealing as soon as the flag is set
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// Deals a random solvable configuration on the given map
+    ///
+    /// `progress` is called at the start of each attempt and periodically during it.
+    pub fn deal(&self, map: Map, seed: u64, progress: &mut dyn FnMut(&Progress)) -> Result<Board> {
+        // map is kept untouched so that dealing it again yields the same tiles
+        let mut tiles: Vec<_> = map
+            .slots_in_draw_order()
+            .into_iter()
+            .map(|slot| Tile {
+                id: 0,
+                slot,
+                visible: true,
+            })
+            .collect();
+        let mut exposure = Exposure::from_tiles(&tiles);
+
+        // ChaCha is used because StdRng is not guaranteed to be reproducible across versions
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+
+        // it is based on the behavior of KMahjongg, not GNOME Mahjongg
+        let difficulty = self.difficulty;
+        let pairs: Vec<_> = std::iter::repeat_with(|| {
+            if difficulty == Difficulty::Easy {
+                // both pairs of a kind follow each other, so that all four tiles are open together
+                let mut kinds: Vec<usize> = (0..144 / 4).collect();
+                kinds.shuffle(&mut rng);
+                kinds
+                    .into_iter()
+                    .flat_map(|kind| [2 * kind, 2 * kind + 1])
+                    .collect()
+            } else {
+                let mut pairs: Vec<usize> = (0..144 / 2).collect();
+                pairs.shuffle(&mut rng);
+                pairs
+            }
+        })
+        .flatten()
+        .take(tiles.len() / 2)
+        .map(|pair| (2 * pair, 2 * pair + 1))
+        .collect();
+
+        // GNOME Mahjongg version is:
+        // let mut pairs: Vec<usize> = (0..tiles.len() / 2).collect();
+        // pairs.shuffle(rng);
+        // but it doesn't support #tiles > 144 and has more biased tile distribution
+
+        self.fill(&mut tiles, &mut exposure, &pairs, &mut rng, progress)?;
+
+        Ok(Board::from_deal(
+            map,
+            seed,
+            self.difficulty,
+            tiles,
+            rng,
+            exposure,
+        ))
+    }
+
+    /// Gives pairs of ids to visible tiles so that they can all be removed
+    ///
+    /// Ids of visible tiles have to be the same beforehand so that any two of them match.
+    /// Tiles are left visible whether or not it succeeds.
+    pub(crate) fn fill<R: Rng + ?Sized>(
+        &self,
+        tiles: &mut [Tile],
+        exposure: &mut Exposure,
+        pairs: &[(usize, usize)],
+        rng: &mut R,
+        progress: &mut dyn FnMut(&Progress),
+    ) -> Result<()> {
+        let visible: Vec<_> = (0..tiles.len()).filter(|&i| tiles[i].visible).collect();
+
+        let mut search = Search {
+            tiles,
+            exposure,
+            pairs,
+            rng,
+            progress,
+            cancel: self.cancel.as_deref(),
+            difficulty: self.difficulty,
+            strategy: Strategy::Random,
+            attempt: 0,
+            steps: 0,
+            max_steps: self.max_steps,
+            placed: 0,
+            dead_ends: HashSet::new(),
+            outcome: Outcome::Exhausted,
+        };
+
+        let mut outcome = Outcome::OutOfSteps;
+        for _ in 0..=self.restarts {
+            outcome = search.run(Strategy::Random, self.max_steps);
+            if outcome != Outcome::OutOfSteps {
+                break;
+            }
+        }
+        if outcome == Outcome::OutOfSteps {
+            outcome = search.run(Strategy::Systematic, self.fallback_steps);
+        }
+
+        for i in visible {
+            exposure.set_visible(tiles, i, true);
+        }
+
+        match outcome {
+            Outcome::Found => Ok(()),
+            Outcome::Exhausted => Err(anyhow!("No solvable configuration")),
+            Outcome::OutOfSteps => Err(anyhow!("No solvable configuration found within budget")),
+            Outcome::Cancelled => Err(anyhow!("Dealing cancelled")),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Outcome {
+    Found,
+    /// Every way of placing pairs has been tried
+    Exhausted,
+    OutOfSteps,
+    Cancelled,
+}
+
+struct Search<'a, R: ?Sized> {
+    tiles: &'a mut [Tile],
+    exposure: &'a mut Exposure,
+    pairs: &'a [(usize, usize)],
+    rng: &'a mut R,
+    progress: &'a mut dyn FnMut(&Progress),
+    cancel: Option<&'a AtomicBool>,
+    difficulty: Difficulty,
+    strategy: Strategy,
+    attempt: usize,
+    steps: usize,
+    max_steps: usize,
+    placed: usize,
+    /// Visible-tile bitsets of positions known to be dead ends, used by the systematic search
+    dead_ends: HashSet<Vec<u64>>,
+    /// Set when the search stops before finding a deal
+    outcome: Outcome,
+}
+
+impl<'a, R: Rng + ?Sized> Search<'a, R> {
+    /// Makes an attempt from the current position, which is restored unless a deal is found
+    fn run(&mut self, strategy: Strategy, max_steps: usize) -> Outcome {
+        self.strategy = strategy;
+        self.attempt += 1;
+        self.steps = 0;
+        self.max_steps = max_steps;
+        self.placed = 0;
+        self.dead_ends.clear();
+        self.outcome = Outcome::Exhausted;
+        self.report();
+
+        if self.search(0) {
+            Outcome::Found
+        } else {
+            self.outcome
+        }
+    }
+
+    fn search(&mut self, depth: usize) -> bool {
+        if depth == self.pairs.len() {
+            return true;
+        }
+
+        self.steps += 1;
+        self.placed = self.placed.max(depth);
+        if self
+            .cancel
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            self.outcome = Outcome::Cancelled;
+            return false;
+        }
+        if self.steps > self.max_steps {
+            self.outcome = Outcome::OutOfSteps;
+            return false;
+        }
+        if self.steps.is_multiple_of(PROGRESS_INTERVAL) {
+            self.report();
+        }
+
+        let key = match self.strategy {
+            Strategy::Random => None,
+            Strategy::Systematic => Some(board::visible_key(self.tiles)),
+        };
+        if key.as_ref().is_some_and(|key| self.dead_ends.contains(key)) {
+            return false;
+        }
+
+        let mut matches = board::find_all_matches(self.tiles, self.exposure);
+        matches.shuffle(self.rng);
+        // stable sorts keep the random order among equally preferred matches
+        match (self.strategy, self.difficulty) {
+            (Strategy::Systematic, _) => {
+                let tiles = &*self.tiles;
+                matches.sort_by_key(|m| Reverse(tiles[m.0].slot.z + tiles[m.1].slot.z));
+            }
+            (Strategy::Random, Difficulty::Easy) => matches.sort_by_cached_key(|m| {
+                (
+                    self.count_stacked(*m, depth),
+                    Reverse(self.count_open_after(*m)),
+                )
+            }),
+            (Strategy::Random, Difficulty::Medium) => (),
+            (Strategy::Random, Difficulty::Hard) => matches.sort_by_cached_key(|m| {
+                (
+                    Reverse(self.count_stacked(*m, depth)),
+                    self.count_open_after(*m),
+                )
+            }),
+        }
+
+        for m in matches {
+            self.tiles[m.0].id = self.pairs[depth].0;
+            self.exposure.set_visible(self.tiles, m.0, false);
+            self.tiles[m.1].id = self.pairs[depth].1;
+            self.exposure.set_visible(self.tiles, m.1, false);
+
+            if self.search(depth + 1) {
+                return true;
+            }
+
+            self.tiles[m.0].id = 0;
+            self.exposure.set_visible(self.tiles, m.0, true);
+            self.tiles[m.1].id = 0;
+            self.exposure.set_visible(self.tiles, m.1, true);
+
+            if self.outcome != Outcome::Exhausted {
+                return false;
+            }
+        }
+
+        if let Some(key) = key {
+            self.dead_ends.insert(key);
+        }
+        false
+    }
+
+    /// Counts tiles of the match lying right under a tile of the kind placed at the given depth
+    ///
+    /// Such tiles are stacked on a tile of their kind in the deal.
+    fn count_stacked(&self, m: Match, depth: usize) -> usize {
+        let kind = self.pairs[depth].0 / 4;
+        let neighbors = self.exposure.neighbors();
+        [m.0, m.1]
+            .iter()
+            .filter(|&&i| {
+                neighbors.above(i).iter().any(|&j| {
+                    // tiles above have been placed already
+                    !self.tiles[j].visible && self.tiles[j].id / 4 == kind
+                })
+            })
+            .count()
+    }
+
+    /// Counts exposed tiles left after removing the match, which are the moves open in play
+    fn count_open_after(&mut self, m: Match) -> usize {
+        self.exposure.set_visible(self.tiles, m.0, false);
+        self.exposure.set_visible(self.tiles, m.1, false);
+        let open = self.exposure.exposed(self.tiles).len();
+        self.exposure.set_visible(self.tiles, m.0, true);
+        self.exposure.set_visible(self.tiles, m.1, true);
+        open
+    }
+
+    fn report(&mut self) {
+        (self.progress)(&Progress {
+            strategy: self.strategy,
+            attempt: self.attempt,
+            steps: self.steps,
+            placed: self.placed,
+            pairs: self.pairs.len(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map;
+
+    fn ids(difficulty: Difficulty, seed: u64) -> Vec<usize> {
+        let board = Dealer::new()
+            .difficulty(difficulty)
+            .deal(map::default::EASY.clone(), seed, &mut |_| ())
+            .unwrap();
+        board.tiles().iter().map(|tile| tile.id).collect()
+    }
+
+    #[test]
+    fn same_seed_gives_same_deal() {
+        for &difficulty in Difficulty::ALL.iter() {
+            assert_eq!(ids(difficulty, 7), ids(difficulty, 7), "{}", difficulty);
+            assert_ne!(ids(difficulty, 7), ids(difficulty, 8), "{}", difficulty);
+        }
+    }
+
+    fn deal(dealer: Dealer) -> (Result<Board>, Vec<Strategy>) {
+        let mut strategies = Vec::new();
+        let board = dealer.deal(map::default::EASY.clone(), 7, &mut |progress| {
+            strategies.push(progress.strategy)
+        });
+        (board, strategies)
+    }
+
+    #[test]
+    fn falls_back_to_systematic_search() {
+        let (board, strategies) = deal(Dealer::new().max_steps(1).restarts(0));
+        assert!(board.is_ok());
+        assert_eq!(strategies.first(), Some(&Strategy::Random));
+        assert_eq!(strategies.last(), Some(&Strategy::Systematic));
+    }
+
+    #[test]
+    fn stops_when_cancelled() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let (board, _) = deal(Dealer::new().cancel_flag(cancel));
+        assert_eq!(board.unwrap_err().to_string(), "Dealing cancelled");
+    }
+
+    #[test]
+    fn fails_when_out_of_budget() {
+        let (board, _) = deal(Dealer::new().max_steps(1).restarts(0).fallback_steps(1));
+        assert_eq!(
+            board.unwrap_err().to_string(),
+            "No solvable configuration found within budget"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use mahjongg::board::Board;
+use mahjongg::dealer::{Dealer, Progress, Strategy};
+use mahjongg::map::Map;
+use mahjongg::rating::{Rater, Rating};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Deal running on another thread so that the window stays responsive
+///
+/// The deal is rated on the same thread. Dropping it cancels the deal.
+pub struct Dealing {
+    cancel: Arc<AtomicBool>,
+    progress: Receiver<Progress>,
+    latest: Option<Progress>,
+    handle: Option<JoinHandle<Result<(Board, Rating)>>>,
+}
+
+impl Dealing {
+    pub fn start(dealer: Dealer, map: Map, seed: u64) -> Self {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let dealer = dealer.cancel_flag(cancel.clone());
+        let (sender, receiver) = mpsc::channel();
+
+        let handle = thread::spawn(move || {
+            let board = dealer.deal(map, seed, &mut |progress| {
+                // the receiver is gone only if the deal has been given up
+                sender.send(progress.clone()).ok();
+            })?;
+            let rating = Rater::new().rate(&board);
+            Ok((board, rating))
+        });
+
+        Self {
+            cancel,
+            progress: receiver,
+            latest: None,
+            handle: Some(handle),
+        }
+    }
+
+    /// Returns the result once dealing has finished
+    pub fn poll(&mut self) -> Option<Result<(Board, Rating)>> {
+        if let Some(progress) = self.progress.try_iter().last() {
+            self.latest = Some(progress);
+        }
+
+        if !self.handle.as_ref()?.is_finished() {
+            return None;
+        }
+        let handle = self.handle.take()?;
+        Some(
+            handle
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("Dealing panicked"))),
+        )
+    }
+
+    /// Describes how far dealing has got
+    pub fn status(&self) -> String {
+        let progress = if let Some(progress) = &self.latest {
+            progress
+        } else {
+            return String::new();
+        };
+
+        let strategy = match progress.strategy {
+            Strategy::Random => "random",
+            Strategy::Systematic => "systematic",
+        };
+        format!(
+            "Attempt {} ({}): {}/{} pairs placed",
+            progress.attempt, strategy, progress.placed, progress.pairs
+        )
+    }
+}
+
+impl Drop for Dealing {
+    fn drop(&mut self) {
+        // the thread finishes on its own once it notices
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
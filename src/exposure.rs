@@ -0,0 +1,146 @@
+use crate::board::Tile;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Tiles that can block each tile, found once from the slots
+#[derive(Debug, Default)]
+pub(crate) struct Neighbors {
+    /// Tiles on the layer above overlapping each tile
+    above: Vec<Vec<usize>>,
+    /// Tiles on the layer below overlapped by each tile, the inverse of `above`
+    below: Vec<Vec<usize>>,
+    /// Tiles adjacent on the left on the same layer
+    left: Vec<Vec<usize>>,
+    /// Tiles adjacent on the right on the same layer
+    right: Vec<Vec<usize>>,
+}
+
+impl Neighbors {
+    pub fn new(tiles: &[Tile]) -> Self {
+        let mut index: HashMap<(isize, isize, isize), Vec<usize>> = HashMap::new();
+        for (i, tile) in tiles.iter().enumerate() {
+            index
+                .entry((tile.slot.x, tile.slot.y, tile.slot.z))
+                .or_default()
+                .push(i);
+        }
+
+        // tiles are 2x2 units large, so a tile overlaps those within one unit in each direction
+        let find = |i: usize, dxs: &[isize], dz: isize| {
+            let slot = &tiles[i].slot;
+            let mut found = Vec::new();
+            for dx in dxs {
+                for dy in -1..=1 {
+                    if let Some(indices) = index.get(&(slot.x + dx, slot.y + dy, slot.z + dz)) {
+                        found.extend(indices.iter().copied().filter(|&j| j != i));
+                    }
+                }
+            }
+            found.sort_unstable();
+            found
+        };
+
+        let above: Vec<_> = (0..tiles.len()).map(|i| find(i, &[-1, 0, 1], 1)).collect();
+        let mut below = vec![Vec::new(); tiles.len()];
+        for (i, uppers) in above.iter().enumerate() {
+            for &upper in uppers {
+                below[upper].push(i);
+            }
+        }
+
+        Self {
+            above,
+            below,
+            left: (0..tiles.len()).map(|i| find(i, &[-2], 0)).collect(),
+            right: (0..tiles.len()).map(|i| find(i, &[2], 0)).collect(),
+        }
+    }
+
+    /// Returns the tiles on the layer above overlapping the tile
+    pub fn above(&self, index: usize) -> &[usize] {
+        &self.above[index]
+    }
+
+    /// Returns the tiles on the layer below overlapped by the tile
+    pub fn below(&self, index: usize) -> &[usize] {
+        &self.below[index]
+    }
+
+    /// Returns the tiles adjacent on either side on the same layer
+    pub fn beside(&self, index: usize) -> impl Iterator<Item = &usize> {
+        self.left[index].iter().chain(&self.right[index])
+    }
+}
+
+/// Number of visible blockers of each tile, kept up to date as tiles are removed and put back
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Exposure {
+    neighbors: Arc<Neighbors>,
+    above: Vec<u8>,
+    left: Vec<u8>,
+    right: Vec<u8>,
+}
+
+impl Exposure {
+    pub fn new(neighbors: Arc<Neighbors>, tiles: &[Tile]) -> Self {
+        let count = |lists: &[Vec<usize>]| -> Vec<u8> {
+            lists
+                .iter()
+                .map(|list| list.iter().filter(|&&j| tiles[j].visible).count() as u8)
+                .collect()
+        };
+
+        Self {
+            above: count(&neighbors.above),
+            left: count(&neighbors.left),
+            right: count(&neighbors.right),
+            neighbors,
+        }
+    }
+
+    /// Builds neighbors from scratch
+    pub fn from_tiles(tiles: &[Tile]) -> Self {
+        Self::new(Arc::new(Neighbors::new(tiles)), tiles)
+    }
+
+    pub fn neighbors(&self) -> &Arc<Neighbors> {
+        &self.neighbors
+    }
+
+    /// Returns true if the tile is visible and can be removed
+    pub fn is_exposed(&self, index: usize, tiles: &[Tile]) -> bool {
+        tiles[index].visible
+            && self.above[index] == 0
+            && (self.left[index] == 0 || self.right[index] == 0)
+    }
+
+    /// Returns indices of exposed tiles in ascending order
+    pub fn exposed(&self, tiles: &[Tile]) -> Vec<usize> {
+        (0..tiles.len())
+            .filter(|&i| self.is_exposed(i, tiles))
+            .collect()
+    }
+
+    /// Shows or hides a tile, updating the counts of tiles it blocks
+    pub fn set_visible(&mut self, tiles: &mut [Tile], index: usize, visible: bool) {
+        if tiles[index].visible == visible {
+            return;
+        }
+        tiles[index].visible = visible;
+
+        let update = |counts: &mut [u8], indices: &[usize]| {
+            for &j in indices {
+                if visible {
+                    counts[j] += 1;
+                } else {
+                    counts[j] -= 1;
+                }
+            }
+        };
+        let neighbors = &self.neighbors;
+        // the tile is above those below it, and on the left of those on its right
+        update(&mut self.above, &neighbors.below[index]);
+        update(&mut self.left, &neighbors.right[index]);
+        update(&mut self.right, &neighbors.left[index]);
+    }
+}
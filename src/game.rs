@@ -1,127 +1,1043 @@
-use super::map::{self, Map, Slot};
+use crate::animation::{self, Animations, Durations};
+use crate::background::Backgrounds;
+use crate::dealing::Dealing;
+use crate::tileset::Tileset;
 use anyhow::{anyhow, Result};
+use mahjongg::board::{Board, Match, State};
+use mahjongg::clock::{self, Clock};
+use mahjongg::dealer::{Dealer, Difficulty};
+use mahjongg::highscores::{self, HighScores};
+use mahjongg::map::{self, Map, Slot};
+use mahjongg::rating::{self, Rating};
+use mahjongg::replay::{self, Action, Player, Replay};
+use mahjongg::save::{self, SaveData};
+use mahjongg::score::Score;
+use mahjongg::solver::Solver;
+use mahjongg::stats::{self, MapStats, Outcome, Statistics};
 use piston_window::*;
-use rand::prelude::*;
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Time for ranking all the moves of a position when a hint is asked for
+const HINT_SOLVER_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// How long the window size has to stay unchanged before tiles are rendered for it
+const TILESET_RENDER_DELAY: Duration = Duration::from_millis(250);
+
+/// Number of high score entries shown after winning
+const SHOWN_HIGH_SCORES: usize = 5;
 
 pub struct Game {
-    map: Map,
+    board: Board,
     background_color: [f32; 4],
-    tiles: Vec<Tile>,
-    theme_texture: G2dTexture,
-    selected: Option<usize>,
+    backgrounds: Backgrounds,
+    tileset: Tileset,
+    /// Tile image size the tileset has been rendered for
+    tile_image_size: Option<[u32; 2]>,
+    /// Tile image size of the current window size and since when it has been
+    pending_tile_image_size: Option<([u32; 2], Instant)>,
     cursor_pos: Option<[f64; 2]>,
-    history: Vec<(usize, usize)>,
+    /// Tile focused by keyboard navigation
+    focus: Option<usize>,
+    solver: Solver,
+    /// Available moves, ranked when hint is first requested
+    hints: Vec<Match>,
+    /// Index of currently shown hint
+    hint: Option<usize>,
+    state: State,
+    glyphs: Option<Glyphs>,
+    ctrl_pressed: bool,
+    shift_pressed: bool,
+    clock: Clock,
+    score: Score,
+    focused: bool,
+    /// Whether the outcome has been recorded in high scores and statistics
+    recorded: bool,
+    /// Top entries for the map, shown after winning
+    high_scores: Vec<highscores::Entry>,
+    /// Rank of this game in `high_scores`
+    rank: Option<usize>,
+    /// Statistics of the map, shown while toggled on
+    shown_stats: Option<MapStats>,
+    /// Recording of this game, if it has been recorded from the initial deal
+    replay: Option<Replay>,
+    /// Set when playing back a replay instead of playing
+    player: Option<Player>,
+    animations: Animations,
+    /// Visibility of tiles when the board was last changed, to find out what to animate
+    shown: Vec<bool>,
+    /// Whether tiles that cannot be selected are drawn darker
+    dim_blocked: bool,
+    /// Whether tiles matching the selected one are marked
+    highlight_matches: bool,
+    /// Deal of the next game, replacing this one once finished
+    dealing: Option<Dealing>,
+    /// Difficulty chosen in the new game menu while it is open
+    new_game_menu: Option<Difficulty>,
+    /// Outcome of the last action that has no other visible effect, shown until the board changes
+    notice: Option<String>,
+    /// Whether this game is the one saved on exit on its map, removed once the game ends
+    autosaved: bool,
+}
+
+/// Direction of keyboard navigation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Direction {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+/// Reason for leaving the game loop
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Exit {
+    Quit,
+    ChangeMap,
+}
+
+/// Error of a deal given up by the player while waiting for it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Dealing cancelled")
+    }
 }
 
+impl std::error::Error for Cancelled {}
+
 impl Game {
-    pub fn run(&mut self, mut window: &mut PistonWindow) {
+    pub fn run(&mut self, window: &mut PistonWindow) -> Exit {
+        window.set_title(self.title());
+        self.update_clock();
+
         while let Some(event) = window.next() {
             if event.render_args().is_some() {
-                self.draw(&mut window, &event);
+                self.update_tileset(window);
+                self.draw(window, &event);
+                self.draw_overlay(window, &event);
             }
 
             if let Some(pos) = event.mouse_cursor_args() {
                 self.on_mouse_cursor(pos);
             }
 
+            if let Some(focused) = event.focus_args() {
+                self.focused = focused;
+                // modifiers released in another window never report their release here
+                self.ctrl_pressed = false;
+                self.shift_pressed = false;
+                self.update_clock();
+            }
+
+            if let Some(args) = event.update_args() {
+                self.on_update(args.dt);
+            }
+
             if let Some(button) = event.press_args() {
+                if self.player.is_some() {
+                    self.on_playback_press(button);
+                    continue;
+                }
+
+                if self.dealing.is_some() {
+                    match button {
+                        Button::Keyboard(Key::Escape) => self.cancel_dealing(),
+                        Button::Keyboard(Key::Q) => return Exit::Quit,
+                        _ => (),
+                    }
+                    continue;
+                }
+
+                if self.new_game_menu.is_some() {
+                    self.on_new_game_menu_press(button);
+                    continue;
+                }
+
+                let title = self.title();
+
                 match button {
-                    Button::Mouse(MouseButton::Left) => self.on_left_click(&mut window),
-                    Button::Mouse(MouseButton::Right) => self.on_right_click(),
+                    Button::Mouse(MouseButton::Left) => self.on_left_click(window),
+                    Button::Mouse(MouseButton::Right) => self.on_undo(),
+                    Button::Mouse(MouseButton::Middle) | Button::Keyboard(Key::H) => self.on_hint(),
+                    Button::Keyboard(Key::LCtrl | Key::RCtrl) => self.ctrl_pressed = true,
+                    Button::Keyboard(Key::LShift | Key::RShift) => self.shift_pressed = true,
+                    Button::Keyboard(Key::Z) if self.ctrl_pressed => {
+                        if self.shift_pressed {
+                            self.on_redo()
+                        } else {
+                            self.on_undo()
+                        }
+                    }
+                    Button::Keyboard(Key::Backspace) => self.on_undo(),
+                    Button::Keyboard(Key::Home) => self.on_undo_all(),
+                    Button::Keyboard(Key::End) => self.on_redo_all(),
+                    Button::Keyboard(Key::S) if self.ctrl_pressed => self.on_save(),
+                    Button::Keyboard(Key::O) if self.ctrl_pressed => self.on_load(),
+                    Button::Keyboard(Key::S) => self.on_shuffle(),
+                    Button::Keyboard(Key::R) => self.on_restart(),
+                    Button::Keyboard(Key::N) => {
+                        self.new_game_menu = Some(self.board.difficulty());
+                        self.update_clock();
+                    }
+                    Button::Keyboard(Key::M) => {
+                        // the clock resumes when returning to the game
+                        self.clock.pause();
+                        return Exit::ChangeMap;
+                    }
+                    Button::Keyboard(Key::Q) => return Exit::Quit,
+                    Button::Keyboard(Key::Left) => self.on_move_focus(window, Direction::Left),
+                    Button::Keyboard(Key::Right) => self.on_move_focus(window, Direction::Right),
+                    Button::Keyboard(Key::Up) => self.on_move_focus(window, Direction::Up),
+                    Button::Keyboard(Key::Down) => self.on_move_focus(window, Direction::Down),
+                    Button::Keyboard(Key::Return | Key::NumPadEnter | Key::Space) => {
+                        self.on_select_focused()
+                    }
+                    Button::Keyboard(Key::Escape) => self.board.deselect(),
+                    Button::Keyboard(Key::T) => self.on_toggle_stats(),
+                    Button::Keyboard(Key::D) => self.dim_blocked = !self.dim_blocked,
+                    Button::Keyboard(Key::B) => {
+                        let notice = if self.shift_pressed {
+                            self.backgrounds.placement = self.backgrounds.placement.next();
+                            format!("Background placement: {}", self.backgrounds.placement)
+                        } else {
+                            self.backgrounds.select_next();
+                            match self.backgrounds.current() {
+                                Some(background) => format!("Background: {}", background.name),
+                                None => "Background: plain color".to_string(),
+                            }
+                        };
+                        self.notice = Some(notice);
+                    }
+                    Button::Keyboard(Key::P) => self.highlight_matches = !self.highlight_matches,
+                    _ => (),
+                }
+
+                if self.title() != title {
+                    window.set_title(self.title());
+                }
+            }
+
+            if let Some(Button::Keyboard(key)) = event.release_args() {
+                match key {
+                    Key::LCtrl | Key::RCtrl => self.ctrl_pressed = false,
+                    Key::LShift | Key::RShift => self.shift_pressed = false,
                     _ => (),
                 }
             }
         }
+
+        Exit::Quit
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Returns true if this game is the one saved on exit on its map
+    pub fn is_autosaved(&self) -> bool {
+        self.autosaved
+    }
+
+    /// Returns the background images with the choice made in this game
+    pub fn backgrounds(&self) -> &Backgrounds {
+        &self.backgrounds
+    }
+
+    pub fn map(&self) -> &Map {
+        self.board.map()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    pub fn points(&self) -> i64 {
+        self.score.points(&self.board, self.elapsed().as_secs())
+    }
+
+    pub fn save_data(&self) -> SaveData {
+        SaveData::new(
+            self.board.clone(),
+            self.elapsed(),
+            self.score.clone(),
+            self.replay.clone(),
+        )
+    }
+
+    fn title(&self) -> String {
+        let title = format!(
+            "{} (seed: {}, difficulty: {})",
+            self.board.map().name,
+            self.board.seed(),
+            self.board.difficulty()
+        );
+        if self.player.is_some() {
+            return format!("Replay: {}", title);
+        }
+
+        match self.state {
+            State::Playing => title,
+            State::Won => format!("{} - You won!", title),
+            State::Stuck => format!("{} - No moves left", title),
+        }
     }
 
     fn draw(&self, window: &mut PistonWindow, event: &Event) {
         let geometry = self.calc_geometry(window.draw_size());
         let width = geometry.image_size.width;
         let height = geometry.image_size.height;
+        let draw_size = window.draw_size();
+
+        let hovered = if self.player.is_none() && self.state == State::Playing {
+            self.get_pointed_tile_index(window.draw_size())
+                .filter(|&index| self.board.is_exposed(index))
+        } else {
+            None
+        };
 
         window.draw_2d(event, |c, g, _| {
             clear(self.background_color, g);
+            if let Some(background) = self.backgrounds.current() {
+                background.draw(self.backgrounds.placement, draw_size, &c, g);
+            }
 
-            for (i, tile) in self.tiles.iter().enumerate() {
-                if !tile.visible {
+            for (i, tile) in self.board.tiles().iter().enumerate() {
+                let effect = self.animations.effect(i);
+                if !tile.visible && effect.is_none() {
                     continue;
                 }
+                let effect = effect.unwrap_or_default();
 
                 let pos = geometry.calc_tile_pos(&tile.slot);
+                let pos = Position::from([
+                    pos.x + (effect.offset[0] * geometry.tile_size.width) as i32,
+                    pos.y + (effect.offset[1] * geometry.tile_size.height) as i32,
+                ]);
+
+                let shade = if self.dim_blocked && tile.visible && !self.board.is_exposed(i) {
+                    0.55
+                } else {
+                    1.0
+                };
+                draw_tile(
+                    &self.tileset,
+                    &geometry,
+                    pos,
+                    tile.id,
+                    self.is_highlighted(i),
+                    [shade, shade, shade, effect.alpha as f32],
+                    &c,
+                    g,
+                );
+            }
 
-                let draw_state =
-                    c.draw_state
-                        .scissor([pos.x as u32, pos.y as u32, width as u32, height as u32]);
+            if let Some(hovered) = hovered {
+                let face = geometry.calc_face_rect(&self.board.tiles()[hovered].slot);
+                rectangle([1.0, 1.0, 1.0, 0.2], face, c.transform, g);
+            }
 
-                let texture_x = get_image_offset(tile.id) as f64 * width;
-                let texture_y = self
-                    .selected
-                    .map(|s| if s == i { height } else { 0.0 })
-                    .unwrap_or(0.0);
-                let transform = c
-                    .transform
-                    .trans(pos.x as f64 - texture_x, pos.y as f64 - texture_y)
-                    .scale(geometry.image_scale.width, geometry.image_scale.height);
+            for (partner, exposed) in self.matching_tiles() {
+                let face = geometry.calc_face_rect(&self.board.tiles()[partner].slot);
+                if exposed {
+                    rectangle([0.3, 1.0, 0.3, 0.25], face, c.transform, g);
+                    Rectangle::new_border([0.3, 1.0, 0.3, 1.0], 2.0).draw(
+                        face,
+                        &c.draw_state,
+                        c.transform,
+                        g,
+                    );
+                } else {
+                    Rectangle::new_border([1.0, 0.45, 0.3, 0.9], 1.0).draw(
+                        face,
+                        &c.draw_state,
+                        c.transform,
+                        g,
+                    );
+                }
+            }
 
-                Image::new().draw(&self.theme_texture, &draw_state, transform, g);
+            if let Some(focus) = self.focus.filter(|&focus| self.board.is_exposed(focus)) {
+                let pos = geometry.calc_tile_pos(&self.board.tiles()[focus].slot);
+                Rectangle::new_border([1.0, 0.85, 0.2, 1.0], 2.0).draw(
+                    [pos.x as f64, pos.y as f64, width, height],
+                    &c.draw_state,
+                    c.transform,
+                    g,
+                );
             }
         });
     }
 
+    fn draw_overlay(&mut self, window: &mut PistonWindow, event: &Event) {
+        const WHITE: [f32; 4] = [1.0; 4];
+        const YELLOW: [f32; 4] = [1.0, 0.85, 0.2, 1.0];
+
+        let status = if let Some(player) = &self.player {
+            format!(
+                "Replay: {}/{}    Speed: x{}{}    Space: pause    Left/Right: step    Up/Down: speed",
+                player.position(),
+                player.replay().events.len(),
+                player.speed(),
+                if player.is_paused() { " (paused)" } else { "" }
+            )
+        } else {
+            let rating = self
+                .rating()
+                .map(|rating| format!("    Rating: {}", rating.score()))
+                .unwrap_or_default();
+            let notice = self
+                .notice
+                .as_ref()
+                .map(|notice| format!("    {}", notice))
+                .unwrap_or_default();
+            format!(
+                "Time: {}    Score: {}{}{}",
+                clock::format_duration(self.elapsed()),
+                self.points(),
+                rating,
+                notice
+            )
+        };
+        let (message, help) = match self.state {
+            _ if self.dealing.is_some() => (Some("Dealing..."), "Esc: cancel"),
+            _ if self.player.is_some() => (None, ""),
+            State::Playing => (None, ""),
+            State::Won => (
+                Some("You won!"),
+                "Right click: undo    R: restart    N: new game    M: change map",
+            ),
+            State::Stuck => (
+                Some("No moves left"),
+                "Right click: undo    S: shuffle    R: restart    N: new game    M: change map",
+            ),
+        };
+
+        let mut lines = Vec::new();
+        if let Some(selected) = self.new_game_menu {
+            lines.push(("New game".to_string(), 48, WHITE));
+            for (i, &difficulty) in Difficulty::ALL.iter().enumerate() {
+                let color = if difficulty == selected {
+                    YELLOW
+                } else {
+                    WHITE
+                };
+                lines.push((
+                    format!("{}.  {}", i + 1, difficulty_label(difficulty)),
+                    24,
+                    color,
+                ));
+            }
+            lines.push((
+                "Up/Down: choose    Enter: deal    Esc: back".to_string(),
+                18,
+                WHITE,
+            ));
+        } else if let Some(map_stats) = &self.shown_stats {
+            lines = stats_lines(&self.board.map().name, map_stats)
+                .into_iter()
+                .map(|line| (line, 18, WHITE))
+                .collect();
+            lines[0].1 = 32;
+        } else if let Some(message) = message {
+            let status = match &self.dealing {
+                Some(dealing) => dealing.status(),
+                None => status.clone(),
+            };
+            lines.push((message.to_string(), 48, WHITE));
+            lines.push((status, 24, WHITE));
+            lines.push((help.to_string(), 18, WHITE));
+
+            if self.state == State::Won && !self.high_scores.is_empty() {
+                lines.push(("High scores".to_string(), 24, WHITE));
+                for (i, entry) in self.high_scores.iter().enumerate() {
+                    let text = format!(
+                        "{}.  {}  {}",
+                        i + 1,
+                        entry.points,
+                        clock::format_duration(entry.time)
+                    );
+                    let color = if self.rank == Some(i) { YELLOW } else { WHITE };
+                    lines.push((text, 18, color));
+                }
+            }
+        }
+
+        let size = window.draw_size();
+        let glyphs = &mut self.glyphs;
+
+        window.draw_2d(event, |c, g, device| {
+            if !lines.is_empty() {
+                rectangle(
+                    [0.0, 0.0, 0.0, 0.6],
+                    [0.0, 0.0, size.width, size.height],
+                    c.transform,
+                    g,
+                );
+            }
+
+            let glyphs = if let Some(glyphs) = glyphs {
+                glyphs
+            } else {
+                return;
+            };
+
+            if lines.is_empty() {
+                Text::new_color(WHITE, 18)
+                    .draw(
+                        &status,
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(12.0, 28.0),
+                        g,
+                    )
+                    .ok();
+            } else {
+                draw_centered_lines(&lines, glyphs, size, &c, g);
+            }
+
+            glyphs.factory.encoder.flush(device);
+        });
+    }
+
+    fn is_highlighted(&self, index: usize) -> bool {
+        if self.board.selected() == Some(index) {
+            return true;
+        }
+
+        self.hint
+            .map(|hint| {
+                let m = self.hints[hint];
+                m.0 == index || m.1 == index
+            })
+            .unwrap_or(false)
+    }
+
+    /// Returns the tiles matching the selected one and whether each is exposed
+    fn matching_tiles(&self) -> Vec<(usize, bool)> {
+        let selected = match self.board.selected() {
+            Some(selected) if self.highlight_matches => selected,
+            _ => return Vec::new(),
+        };
+
+        let tiles = self.board.tiles();
+        tiles
+            .iter()
+            .enumerate()
+            .filter(|&(i, tile)| i != selected && tile.visible && tile.matches(&tiles[selected]))
+            .map(|(i, _)| (i, self.board.is_exposed(i)))
+            .collect()
+    }
+
     fn on_mouse_cursor(&mut self, pos: [f64; 2]) {
         self.cursor_pos = Some(pos);
     }
 
     fn on_left_click(&mut self, window: &mut PistonWindow) {
-        let pointed = if let Some(pointed) = self.get_pointed_tile_index(window.draw_size()) {
-            pointed
+        if let Some(pointed) = self.get_pointed_tile_index(window.draw_size()) {
+            self.select(pointed);
+        }
+    }
+
+    fn select(&mut self, index: usize) {
+        let prev = self.board.selected();
+        if self.board.select(index).is_some() {
+            self.record_last_move();
+            self.on_board_changed();
+        } else if !self.board.is_exposed(index) || prev.is_some_and(|prev| prev != index) {
+            // blocked tile or no match
+            self.animations.start(index, animation::Kind::Shake);
+        }
+    }
+
+    /// Moves the focus to the nearest exposed tile in the given direction on screen
+    fn on_move_focus(&mut self, window: &PistonWindow, direction: Direction) {
+        let geometry = self.calc_geometry(window.draw_size());
+        let center = |index: usize| {
+            let pos = geometry.calc_tile_pos(&self.board.tiles()[index].slot);
+            (
+                pos.x as f64 + geometry.tile_size.width / 2.0,
+                pos.y as f64 + geometry.tile_size.height / 2.0,
+            )
+        };
+        let exposed = (0..self.board.tiles().len()).filter(|&i| self.board.is_exposed(i));
+
+        let focus = if let Some(focus) = self.focus {
+            let (x, y) = center(focus);
+            exposed
+                .filter(|&i| i != focus)
+                .filter_map(|i| {
+                    let (dx, dy) = center(i);
+                    let (dx, dy) = (dx - x, dy - y);
+                    let (along, across) = match direction {
+                        Direction::Left => (-dx, dy),
+                        Direction::Right => (dx, dy),
+                        Direction::Up => (-dy, dx),
+                        Direction::Down => (dy, dx),
+                    };
+                    // prefer tiles in line with the focused one
+                    if along > 0.0 {
+                        Some((i, along + across.abs() * 2.0))
+                    } else {
+                        None
+                    }
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i)
         } else {
-            // points to nothing
-            return;
+            // start from the top left corner
+            exposed.min_by(|&a, &b| {
+                let (ax, ay) = center(a);
+                let (bx, by) = center(b);
+                (ax + ay).total_cmp(&(bx + by))
+            })
         };
 
-        if !tile_is_exposed(pointed, &self.tiles) {
-            // pointed tile is not removable
+        if focus.is_some() {
+            self.focus = focus;
+        }
+    }
+
+    fn on_select_focused(&mut self) {
+        if let Some(focus) = self.focus {
+            self.select(focus);
+        }
+    }
+
+    fn on_undo(&mut self) {
+        if self.board.undo().is_some() {
+            self.score.undos += 1;
+            self.record(Action::Undo);
+            self.on_board_changed();
+        }
+    }
+
+    fn on_redo(&mut self) {
+        if self.board.redo().is_some() {
+            self.record(Action::Redo);
+            self.on_board_changed();
+        }
+    }
+
+    fn on_undo_all(&mut self) {
+        let len = self.board.history().len();
+        if self.board.undo_all() {
+            self.score.undos += len;
+            for _ in 0..len {
+                self.record(Action::Undo);
+            }
+            self.on_board_changed();
+        }
+    }
+
+    fn on_redo_all(&mut self) {
+        let len = self.board.redo_stack().len();
+        if self.board.redo_all() {
+            for _ in 0..len {
+                self.record(Action::Redo);
+            }
+            self.on_board_changed();
+        }
+    }
+
+    fn on_hint(&mut self) {
+        if self.hints.is_empty() {
+            self.hints = self.solver.rank_moves(&self.board);
+            if !self.hints.is_empty() {
+                // alternatives for the same position are free
+                self.score.hints += 1;
+            }
+        }
+        if self.hints.is_empty() {
+            // no moves available
+            return;
+        }
+
+        // cycle through alternatives
+        self.hint = Some(
+            self.hint
+                .map(|hint| (hint + 1) % self.hints.len())
+                .unwrap_or(0),
+        );
+        self.board.deselect();
+    }
+
+    fn on_shuffle(&mut self) {
+        if let Err(err) = self.board.shuffle() {
+            self.notice = Some(format!("Failed to shuffle: {}", err));
             return;
         }
+        self.score.shuffles += 1;
+        self.record_last_move();
+        self.on_board_changed();
+    }
+
+    fn on_restart(&mut self) {
+        // dealing the same map with the same seed yields the initial board
+        self.deal(self.board.seed(), self.board.difficulty());
+    }
+
+    fn on_new_game_menu_press(&mut self, button: Button) {
+        let selected = if let Some(selected) = self.new_game_menu {
+            selected
+        } else {
+            return;
+        };
+        let index = Difficulty::ALL
+            .iter()
+            .position(|&difficulty| difficulty == selected)
+            .unwrap_or(0);
+
+        match button {
+            Button::Keyboard(Key::Up) => {
+                self.new_game_menu = Some(Difficulty::ALL[index.saturating_sub(1)])
+            }
+            Button::Keyboard(Key::Down) => {
+                self.new_game_menu =
+                    Some(Difficulty::ALL[(index + 1).min(Difficulty::ALL.len() - 1)])
+            }
+            Button::Keyboard(Key::D1) => self.on_new_game(Difficulty::Easy),
+            Button::Keyboard(Key::D2) => self.on_new_game(Difficulty::Medium),
+            Button::Keyboard(Key::D3) => self.on_new_game(Difficulty::Hard),
+            Button::Keyboard(Key::Return | Key::NumPadEnter | Key::Space) => {
+                self.on_new_game(selected)
+            }
+            Button::Keyboard(Key::Escape) => {
+                self.new_game_menu = None;
+                self.update_clock();
+            }
+            _ => (),
+        }
+    }
+
+    fn on_new_game(&mut self, difficulty: Difficulty) {
+        self.new_game_menu = None;
+        let seed = rand::random();
+        println!("Seed: {}", seed);
+        self.deal(seed, difficulty);
+    }
+
+    /// Starts dealing the map, playing the deal once it is finished
+    fn deal(&mut self, seed: u64, difficulty: Difficulty) {
+        self.dealing = Some(Dealing::start(
+            Dealer::new().difficulty(difficulty),
+            self.board.map().clone(),
+            seed,
+        ));
+        self.update_clock();
+    }
+
+    fn cancel_dealing(&mut self) {
+        self.dealing = None;
+        self.update_clock();
+    }
+
+    fn poll_dealing(&mut self) {
+        let result = match self.dealing.as_mut().and_then(Dealing::poll) {
+            Some(result) => result,
+            None => return,
+        };
+        self.dealing = None;
+
+        match result {
+            Ok((board, rating)) => self.start(board, rating),
+            Err(err) => {
+                self.notice = Some(format!("Failed to deal: {}", err));
+                self.update_clock();
+            }
+        }
+    }
+
+    /// Gives up the current game and starts playing the given board
+    fn start(&mut self, board: Board, rating: Rating) {
+        self.abandon();
+        println!("Difficulty rating: {}", describe_rating(&rating));
+        let mut replay = Replay::new(board.map().clone(), board.seed(), board.difficulty());
+        replay.rating = Some(rating);
+        self.replay = Some(replay);
+        self.board = board;
+        self.clock = Clock::start(Duration::ZERO);
+        self.score = Score::default();
+        self.reset_records();
+        self.record_start();
+        self.on_board_changed();
+    }
+
+    fn reset_records(&mut self) {
+        self.recorded = false;
+        self.high_scores.clear();
+        self.rank = None;
+        self.shown_stats = None;
+    }
 
-        let prev = if let Some(prev) = self.selected {
-            prev
+    fn on_save(&mut self) {
+        let file = if let Some(file) = save::save_file() {
+            file
         } else {
-            // select first of pair
-            self.selected = Some(pointed);
+            self.notice = Some("Failed to save: no data directory".to_string());
             return;
         };
 
-        if pointed == prev {
-            // cancel selection
-            self.selected = None;
+        self.notice = Some(match self.save_data().save(&file) {
+            Ok(()) => format!("Saved to {}", file.display()),
+            Err(err) => format!("Failed to save: {}", err),
+        });
+    }
+
+    fn on_load(&mut self) {
+        let data = match save::save_file().map(SaveData::load) {
+            Some(Ok(data)) => data,
+            Some(Err(err)) => {
+                self.notice = Some(format!("Failed to load: {}", err));
+                return;
+            }
+            None => {
+                self.notice = Some("Failed to load: no data directory".to_string());
+                return;
+            }
+        };
+
+        self.abandon();
+        self.replay = data.replay;
+        self.board = data.board;
+        self.clock = Clock::start(data.elapsed);
+        self.score = data.score;
+        self.reset_records();
+        // a game saved after winning has already been recorded
+        self.recorded = self.board.is_cleared();
+        self.on_board_changed();
+        self.notice = Some("Loaded the saved game".to_string());
+    }
+
+    fn on_board_changed(&mut self) {
+        self.hints.clear();
+        self.hint = None;
+        self.notice = None;
+        self.state = self.board.state();
+        self.update_clock();
+        self.animate_changes();
+
+        if self.state == State::Won && !self.recorded {
+            self.recorded = true;
+            self.record_high_score();
+            self.record_end(Outcome::Won);
+        }
+    }
+
+    /// Gives up the game unless it has already ended
+    pub fn abandon(&mut self) {
+        if self.recorded {
             return;
         }
+        self.recorded = true;
 
-        if self.tiles[pointed].matches(&self.tiles[prev]) {
-            // remove tile
+        self.record_end(if self.state == State::Stuck {
+            Outcome::Stuck
+        } else {
+            Outcome::Abandoned
+        });
+    }
 
-            self.tiles[prev].visible = false;
-            self.tiles[pointed].visible = false;
-            self.history.push((prev, pointed));
+    /// Fades out removed tiles and fades in the ones put back
+    fn animate_changes(&mut self) {
+        let tiles = self.board.tiles();
+        if tiles.len() == self.shown.len() {
+            for (i, tile) in tiles.iter().enumerate() {
+                if tile.visible && !self.shown[i] {
+                    self.animations.start(i, animation::Kind::FadeIn);
+                } else if !tile.visible && self.shown[i] {
+                    self.animations.start(i, animation::Kind::FadeOut);
+                }
+            }
+        } else {
+            // different map, where indices of the old one mean nothing
+            self.animations.clear();
+            self.focus = None;
+        }
+
+        self.shown = tiles.iter().map(|tile| tile.visible).collect();
+    }
+
+    fn record_start(&self) {
+        let map_name = &self.board.map().name;
+        if let Err(err) = stats::update(|stats| stats.record_start(map_name)) {
+            eprintln!("Failed to update statistics: {}", err);
+        }
+    }
 
-            self.selected = None;
+    fn record_end(&mut self, outcome: Outcome) {
+        let map_name = &self.board.map().name;
+        if self.autosaved {
+            self.autosaved = false;
+            if let Err(err) = save::remove_autosave(map_name) {
+                eprintln!("Failed to remove saved game: {}", err);
+            }
+        }
+
+        let result = stats::update(|stats| {
+            stats.record_end(
+                map_name,
+                outcome,
+                &self.score,
+                self.elapsed(),
+                self.rating(),
+            )
+        });
+        if let Err(err) = result {
+            eprintln!("Failed to update statistics: {}", err);
+        }
+
+        if let Some(replay) = &self.replay {
+            save_replay(replay);
+        }
+    }
+
+    fn record(&mut self, action: Action) {
+        let time = self.elapsed();
+        if let Some(replay) = &mut self.replay {
+            replay.push(time, action);
+        }
+    }
+
+    fn record_last_move(&mut self) {
+        let time = self.elapsed();
+        if let Some(replay) = &mut self.replay {
+            replay.push_last_move(time, &self.board);
+        }
+    }
+
+    fn on_update(&mut self, dt: f64) {
+        self.animations.update(dt);
+        self.poll_dealing();
+
+        let changed = self
+            .player
+            .as_mut()
+            .map(|player| player.advance(Duration::from_secs_f64(dt)))
+            .unwrap_or(false);
+        if changed {
+            self.sync_with_player();
+        }
+    }
+
+    fn on_playback_press(&mut self, button: Button) {
+        let player = if let Some(player) = &mut self.player {
+            player
+        } else {
             return;
+        };
+
+        match button {
+            Button::Keyboard(Key::Space) => player.toggle_pause(),
+            Button::Keyboard(Key::Up) => player.faster(),
+            Button::Keyboard(Key::Down) => player.slower(),
+            Button::Keyboard(Key::Right) => {
+                if !player.is_paused() {
+                    player.toggle_pause();
+                }
+                player.step_forward();
+            }
+            Button::Keyboard(Key::Left) => {
+                if !player.is_paused() {
+                    player.toggle_pause();
+                }
+                player.step_back();
+            }
+            Button::Keyboard(Key::Home) => player.seek(0),
+            Button::Keyboard(Key::End) => player.seek(usize::MAX),
+            _ => return,
         }
 
-        // select another tile
-        self.selected = Some(pointed);
+        self.sync_with_player();
     }
 
-    fn on_right_click(&mut self) {
-        // undo
-        if let Some(last) = self.history.pop() {
-            self.tiles[last.0].visible = true;
-            self.tiles[last.1].visible = true;
+    fn sync_with_player(&mut self) {
+        if let Some(player) = &self.player {
+            self.board = player.board().clone();
+            self.hints.clear();
+            self.hint = None;
+            self.state = self.board.state();
+            self.animate_changes();
+        }
+    }
 
-            self.selected = None;
+    fn on_toggle_stats(&mut self) {
+        if self.shown_stats.take().is_some() {
+            return;
+        }
+
+        let loaded = stats::file()
+            .ok_or_else(|| anyhow!("No data directory"))
+            .and_then(Statistics::load);
+        match loaded {
+            Ok(statistics) => {
+                self.shown_stats = Some(
+                    statistics
+                        .get(&self.board.map().name)
+                        .cloned()
+                        .unwrap_or_default(),
+                )
+            }
+            Err(err) => eprintln!("Failed to load statistics: {}", err),
+        }
+    }
+
+    fn record_high_score(&mut self) {
+        let file = if let Some(file) = highscores::file() {
+            file
+        } else {
+            return;
+        };
+        let mut high_scores = match HighScores::load(&file) {
+            Ok(high_scores) => high_scores,
+            Err(err) => {
+                eprintln!("Failed to load high scores: {}", err);
+                return;
+            }
+        };
+
+        let map_name = &self.board.map().name;
+        self.rank = high_scores.add(
+            map_name,
+            highscores::Entry {
+                points: self.points(),
+                time: self.elapsed(),
+                seed: self.board.seed(),
+            },
+        );
+        self.high_scores = high_scores
+            .entries(map_name)
+            .iter()
+            .take(SHOWN_HIGH_SCORES)
+            .cloned()
+            .collect();
+
+        if let Err(err) = high_scores.save(&file) {
+            eprintln!("Failed to save high scores: {}", err);
+        }
+    }
+
+    /// Returns the rating of the initial deal, if known
+    fn rating(&self) -> Option<&Rating> {
+        let replay = match &self.player {
+            Some(player) => Some(player.replay()),
+            None => self.replay.as_ref(),
+        };
+        replay.and_then(|replay| replay.rating.as_ref())
+    }
+
+    /// Runs the clock only while the game is being played
+    fn update_clock(&mut self) {
+        if self.focused
+            && self.state != State::Won
+            && self.dealing.is_none()
+            && self.new_game_menu.is_none()
+        {
+            self.clock.resume();
+        } else {
+            self.clock.pause();
         }
     }
 
@@ -134,7 +1050,7 @@ impl Game {
         let height = geometry.image_size.height;
 
         let mut pointed: Option<usize> = None;
-        for (i, tile) in self.tiles.iter().enumerate() {
+        for (i, tile) in self.board.tiles().iter().enumerate() {
             if !tile.visible {
                 continue;
             }
@@ -153,102 +1069,201 @@ impl Game {
         pointed
     }
 
-    fn calc_geometry(&self, draw_size: Size) -> Geometry {
-        let theme_size = self.theme_texture.get_size();
-        let theme_aspect = (theme_size.1 as f64 / 2.0) / (theme_size.0 as f64 / 43.0);
-        let map_size = Size::from([
-            (self.map.width + 2) as f64,
-            (self.map.height + 2) as f64 * theme_aspect,
-        ]);
-
-        let unit_width = (draw_size.width as f64 / map_size.width)
-            .min(draw_size.height as f64 / map_size.height);
-        let unit_height = unit_width * theme_aspect;
-
-        let tile_size = Size::from([unit_width * 2.0, unit_height * 2.0]);
-        let tile_layer_offset = Position::from([
-            (tile_size.width / 7.0) as i32,
-            (tile_size.height / 10.0) as i32,
-        ]);
-
-        let offset = Position::from([
-            ((draw_size.width as f64 - self.map.width as f64 * unit_width) / 2.0) as i32,
-            ((draw_size.height as f64 - self.map.height as f64 * unit_height) / 2.0) as i32,
-        ]);
-
-        let image_size = Size::from([
-            tile_size.width + tile_layer_offset.x as f64,
-            tile_size.height + tile_layer_offset.y as f64,
-        ]);
+    /// Renders tiles again for the window size once it stops changing
+    fn update_tileset(&mut self, window: &mut PistonWindow) {
+        if !self.tileset.is_scalable() {
+            return;
+        }
 
-        let image_scale = Size::from([
-            (image_size.width * 43.0) / theme_size.0 as f64,
-            (image_size.height * 2.0) / theme_size.1 as f64,
-        ]);
+        let geometry = self.calc_geometry(window.draw_size());
+        let size = [
+            geometry.image_size.width.round() as u32,
+            geometry.image_size.height.round() as u32,
+        ];
+        if self.tile_image_size == Some(size) {
+            self.pending_tile_image_size = None;
+            return;
+        }
 
-        Geometry {
-            offset,
-            tile_size,
-            tile_layer_offset,
-            image_size,
-            image_scale,
+        match self.pending_tile_image_size {
+            Some((pending, since)) if pending == size => {
+                if since.elapsed() >= TILESET_RENDER_DELAY {
+                    if let Err(err) = self.tileset.render_at(window, size) {
+                        eprintln!("Failed to render tiles: {}", err);
+                    }
+                    // not retried on failure
+                    self.tile_image_size = Some(size);
+                    self.pending_tile_image_size = None;
+                }
+            }
+            _ => self.pending_tile_image_size = Some((size, Instant::now())),
         }
     }
-}
-
-#[derive(Debug)]
-struct Tile {
-    id: usize,
-    slot: Slot,
-    visible: bool,
-}
 
-impl Tile {
-    fn matches(&self, other: &Tile) -> bool {
-        self.id / 4 == other.id / 4
+    fn calc_geometry(&self, draw_size: Size) -> Geometry {
+        Geometry::new(
+            self.board.map(),
+            &self.tileset,
+            [0.0, 0.0, draw_size.width, draw_size.height],
+        )
     }
 }
 
-fn tile_is_exposed(index: usize, tiles: &[Tile]) -> bool {
-    if !tiles[index].visible {
-        return false;
+/// Draws lines of (text, font size, color) centered in the window
+fn draw_centered_lines(
+    lines: &[(String, u32, [f32; 4])],
+    glyphs: &mut Glyphs,
+    size: Size,
+    c: &Context,
+    g: &mut G2d,
+) {
+    const LINE_SPACING: f64 = 1.6;
+
+    let total_height: f64 = lines
+        .iter()
+        .map(|(_, font_size, _)| *font_size as f64 * LINE_SPACING)
+        .sum();
+
+    let mut y = (size.height - total_height) / 2.0;
+    for (text, font_size, color) in lines {
+        y += *font_size as f64 * LINE_SPACING;
+        let x = (size.width - glyphs.width(*font_size, text).unwrap_or(0.0)) / 2.0;
+        Text::new_color(*color, *font_size)
+            .draw(text, glyphs, &c.draw_state, c.transform.trans(x, y), g)
+            .ok();
     }
+}
 
-    let slot = &tiles[index].slot;
+/// Shows the progress of dealing until it finishes
+///
+/// Fails with `Cancelled` on Esc or when the window is closed.
+fn wait_for_deal(
+    window: &mut PistonWindow,
+    mut dealing: Dealing,
+    glyphs: &mut Option<Glyphs>,
+    background_color: [f32; 3],
+) -> Result<(Board, Rating)> {
+    const WHITE: [f32; 4] = [1.0; 4];
 
-    let mut blocked_left = false;
-    let mut blocked_right = false;
-    for (i, tile) in tiles.iter().enumerate() {
-        if i == index || !tile.visible {
-            continue;
+    while let Some(event) = window.next() {
+        if let Some(result) = dealing.poll() {
+            return result;
         }
 
-        if tile.slot.z == slot.z + 1
-            && tile.slot.x >= slot.x - 1
-            && tile.slot.x <= slot.x + 1
-            && tile.slot.y >= slot.y - 1
-            && tile.slot.y <= slot.y + 1
-        {
-            return false;
+        if event.render_args().is_some() {
+            let lines = [
+                ("Dealing...".to_string(), 48, WHITE),
+                (dealing.status(), 24, WHITE),
+                ("Esc: cancel".to_string(), 18, WHITE),
+            ];
+            let size = window.draw_size();
+            window.draw_2d(&event, |c, g, device| {
+                clear(
+                    [
+                        background_color[0],
+                        background_color[1],
+                        background_color[2],
+                        1.0,
+                    ],
+                    g,
+                );
+                if let Some(glyphs) = glyphs {
+                    draw_centered_lines(&lines, glyphs, size, &c, g);
+                    glyphs.factory.encoder.flush(device);
+                }
+            });
         }
 
-        if tile.slot.z == slot.z && tile.slot.y >= slot.y - 1 && tile.slot.y <= slot.y + 1 {
-            if tile.slot.x == slot.x - 2 {
-                blocked_left = true;
-            }
-            if tile.slot.x == slot.x + 2 {
-                blocked_right = true;
-            }
-            if blocked_left && blocked_right {
-                return false;
-            }
+        if let Some(Button::Keyboard(Key::Escape)) = event.press_args() {
+            break;
         }
     }
 
-    true
+    Err(Cancelled.into())
+}
+
+fn difficulty_label(difficulty: Difficulty) -> &'static str {
+    match difficulty {
+        Difficulty::Easy => "Easy",
+        Difficulty::Medium => "Medium",
+        Difficulty::Hard => "Hard",
+    }
+}
+
+/// Saves a replay into a new file in the replay directory
+pub fn save_replay(replay: &Replay) {
+    if replay.events.is_empty() {
+        return;
+    }
+
+    let file = if let Some(file) = replay::new_file() {
+        file
+    } else {
+        return;
+    };
+    match replay.save(&file) {
+        Ok(()) => println!("Replay saved to {}", file.display()),
+        Err(err) => eprintln!("Failed to save replay: {}", err),
+    }
+}
+
+pub fn describe_rating(rating: &Rating) -> String {
+    format!(
+        "{} (stuck in {:.0}% of random playouts, {:.1} / {:.1} / {:.1} / {:.1} moves open by quarter, up to {:.1} forced moves in a row)",
+        rating.score(),
+        rating.stuck_rate * 100.0,
+        rating.branching[0],
+        rating.branching[1],
+        rating.branching[2],
+        rating.branching[3],
+        rating.forced_depth
+    )
+}
+
+fn stats_lines(map_name: &str, stats: &MapStats) -> Vec<String> {
+    let average_time = stats
+        .average_win_time()
+        .map(clock::format_duration)
+        .unwrap_or_else(|| "-".to_string());
+    let by_rating = if stats.by_rating.is_empty() {
+        "Won by rating: -".to_string()
+    } else {
+        let bands: Vec<_> = stats
+            .by_rating
+            .iter()
+            .map(|(band, stats)| {
+                format!(
+                    "{}: {}/{}",
+                    rating::band_name(*band),
+                    stats.won,
+                    stats.played
+                )
+            })
+            .collect();
+        format!("Won by rating: {}", bands.join("    "))
+    };
+
+    vec![
+        format!("Statistics of {}", map_name),
+        format!(
+            "Started: {}    Won: {}    Stuck: {}    Abandoned: {}",
+            stats.started, stats.won, stats.stuck, stats.abandoned
+        ),
+        format!("Average time: {}", average_time),
+        format!(
+            "Win streak: {}    Best streak: {}",
+            stats.current_streak, stats.best_streak
+        ),
+        format!(
+            "Hints: {}    Undos: {}    Shuffles: {}",
+            stats.hints, stats.undos, stats.shuffles
+        ),
+        by_rating,
+        "T: close".to_string(),
+    ]
 }
 
-struct Geometry {
+pub struct Geometry {
     offset: Position,
     tile_size: Size,
     tile_layer_offset: Position,
@@ -257,7 +1272,50 @@ struct Geometry {
 }
 
 impl Geometry {
-    fn calc_tile_pos(&self, slot: &Slot) -> Position {
+    /// Fits the map into the area (x, y, width, height) with tiles of the tileset
+    pub fn new(map: &Map, tileset: &Tileset, area: [f64; 4]) -> Self {
+        let face_aspect = tileset.face_aspect();
+        let map_size = Size::from([
+            (map.width + 2) as f64,
+            (map.height + 2) as f64 * face_aspect,
+        ]);
+
+        let unit_width = (area[2] / map_size.width).min(area[3] / map_size.height);
+        let unit_height = unit_width * face_aspect;
+
+        let tile_size = Size::from([unit_width * 2.0, unit_height * 2.0]);
+        let level_offset = tileset.level_offset();
+        let tile_layer_offset = Position::from([
+            (tile_size.width * level_offset[0]) as i32,
+            (tile_size.height * level_offset[1]) as i32,
+        ]);
+
+        let offset = Position::from([
+            (area[0] + (area[2] - map.width as f64 * unit_width) / 2.0) as i32,
+            (area[1] + (area[3] - map.height as f64 * unit_height) / 2.0) as i32,
+        ]);
+
+        let image_size = Size::from([
+            tile_size.width + tile_layer_offset.x as f64,
+            tile_size.height + tile_layer_offset.y as f64,
+        ]);
+
+        let tileset_image_size = tileset.image_size();
+        let image_scale = Size::from([
+            image_size.width / tileset_image_size[0],
+            image_size.height / tileset_image_size[1],
+        ]);
+
+        Self {
+            offset,
+            tile_size,
+            tile_layer_offset,
+            image_size,
+            image_scale,
+        }
+    }
+
+    pub fn calc_tile_pos(&self, slot: &Slot) -> Position {
         Position::from([
             self.offset.x
                 + (slot.x as f64 * self.tile_size.width / 2.0) as i32
@@ -266,35 +1324,63 @@ impl Geometry {
                 - slot.z as i32 * self.tile_layer_offset.y,
         ])
     }
-}
-
-fn get_image_offset(id: usize) -> usize {
-    let set = id / 4;
 
-    // Invalid ids
-    if set >= 36 {
-        unreachable!()
+    /// Returns the rectangle of the tile face, which is above the edges on the left and bottom
+    fn calc_face_rect(&self, slot: &Slot) -> [f64; 4] {
+        let pos = self.calc_tile_pos(slot);
+        [
+            (pos.x + self.tile_layer_offset.x) as f64,
+            pos.y as f64,
+            self.tile_size.width,
+            self.tile_size.height,
+        ]
     }
+}
 
-    /* The bonus tiles have different images for each */
-    if set == 33 {
-        33 + id % 4
-    } else if set == 35 {
-        38 + id % 4
-    } else if set == 34 {
-        /* The white dragons are inbetween the bonus tiles just to be confusing */
-        37
-    } else {
-        /* Everything else is in set order */
-        set
-    }
+/// Draws the image of a tile with the given id at `pos`, taken from the highlighted row if requested
+#[allow(clippy::too_many_arguments)]
+pub fn draw_tile(
+    tileset: &Tileset,
+    geometry: &Geometry,
+    pos: Position,
+    id: usize,
+    highlighted: bool,
+    color: [f32; 4],
+    c: &Context,
+    g: &mut G2d,
+) {
+    let width = geometry.image_size.width;
+    let height = geometry.image_size.height;
+
+    let draw_state =
+        c.draw_state
+            .scissor([pos.x as u32, pos.y as u32, width as u32, height as u32]);
+
+    let [column, row] = tileset.image_position(id, highlighted);
+    let texture_x = column as f64 * width;
+    let texture_y = row as f64 * height;
+    let transform = c
+        .transform
+        .trans(pos.x as f64 - texture_x, pos.y as f64 - texture_y)
+        .scale(geometry.image_scale.width, geometry.image_scale.height);
+
+    Image::new_color(color).draw(tileset.texture(), &draw_state, transform, g);
 }
 
 pub struct GameBuilder<'a> {
     window: &'a mut PistonWindow,
     theme_file: Option<PathBuf>,
+    font_file: Option<PathBuf>,
     map: Map,
+    seed: u64,
+    difficulty: Difficulty,
+    resumed: Option<SaveData>,
+    playback: Option<Replay>,
     background_color: [f32; 3],
+    backgrounds: Backgrounds,
+    animation_durations: Durations,
+    dim_blocked: bool,
+    highlight_matches: bool,
 }
 
 impl<'a> GameBuilder<'a> {
@@ -302,8 +1388,17 @@ impl<'a> GameBuilder<'a> {
         Self {
             window,
             theme_file: None,
+            font_file: None,
             map: map::default::EASY.clone(),
+            seed: rand::random(),
+            difficulty: Difficulty::default(),
+            resumed: None,
+            playback: None,
             background_color: [52.0 / 255.0, 56.0 / 255.0, 91.0 / 255.0],
+            backgrounds: Backgrounds::default(),
+            animation_durations: Durations::default(),
+            dim_blocked: false,
+            highlight_matches: false,
         }
     }
 
@@ -312,56 +1407,87 @@ impl<'a> GameBuilder<'a> {
             .theme_file
             .ok_or_else(|| anyhow!("Theme file not provided"))?;
 
-        let theme_texture = if let Ok(buf) = render_svg(&theme_file) {
-            Texture::from_image(
-                &mut self.window.create_texture_context(),
-                &buf,
-                &TextureSettings::new(),
-            )
-            .map_err(|_| anyhow!("Failed to load texture"))?
+        let tileset = Tileset::load(self.window, &theme_file)?;
+
+        let window = &mut self.window;
+        let mut glyphs = self
+            .font_file
+            .and_then(|font_file| window.load_font(font_file).ok());
+
+        let resumed = self.resumed.is_some();
+        let mut player = None;
+        let (board, elapsed, score, replay) = if let Some(replay) = self.playback {
+            let new_player = Player::new(replay)?;
+            let board = new_player.board().clone();
+            player = Some(new_player);
+            (board, Duration::ZERO, Score::default(), None)
+        } else if let Some(data) = self.resumed {
+            (data.board, data.elapsed, data.score, data.replay)
         } else {
-            Texture::from_path(
-                &mut self.window.create_texture_context(),
-                &theme_file,
-                Flip::None,
-                &TextureSettings::new(),
-            )
-            .map_err(|_| anyhow!("Failed to load texture"))?
+            let mut replay = Replay::new(self.map.clone(), self.seed, self.difficulty);
+            let (board, rating) = wait_for_deal(
+                window,
+                Dealing::start(
+                    Dealer::new().difficulty(self.difficulty),
+                    self.map,
+                    self.seed,
+                ),
+                &mut glyphs,
+                self.background_color,
+            )?;
+            println!("Difficulty rating: {}", describe_rating(&rating));
+            replay.rating = Some(rating);
+            (board, Duration::ZERO, Score::default(), Some(replay))
         };
+        let state = board.state();
+        let shown = board.tiles().iter().map(|tile| tile.visible).collect();
 
-        // sort by draw order
-        self.map
-            .slots
-            .sort_unstable_by(|a, b| a.z.cmp(&b.z).then_with(|| (a.y - b.y).cmp(&(a.x - b.x))));
-
-        let mut tiles: Vec<_> = self
-            .map
-            .slots
-            .iter()
-            .map(|slot| Tile {
-                id: 0,
-                slot: slot.clone(),
-                visible: true,
-            })
-            .collect();
-
-        let mut rng = rand::thread_rng();
-        fill_random_ids(&mut tiles, &mut rng)?;
-
-        let game = Game {
-            map: self.map,
+        let mut game = Game {
+            board,
             background_color: [
                 self.background_color[0],
                 self.background_color[1],
                 self.background_color[2],
                 1.0,
             ],
-            tiles,
-            theme_texture,
-            selected: None,
+            backgrounds: self.backgrounds,
+            tileset,
+            tile_image_size: None,
+            pending_tile_image_size: None,
             cursor_pos: None,
-            history: Vec::new(),
+            focus: None,
+            solver: Solver::new().timeout(HINT_SOLVER_TIMEOUT),
+            hints: Vec::new(),
+            hint: None,
+            state,
+            glyphs,
+            ctrl_pressed: false,
+            shift_pressed: false,
+            clock: Clock::start(elapsed),
+            score,
+            focused: true,
+            recorded: false,
+            high_scores: Vec::new(),
+            rank: None,
+            shown_stats: None,
+            replay,
+            player,
+            animations: Animations::new(self.animation_durations),
+            shown,
+            dim_blocked: self.dim_blocked,
+            highlight_matches: self.highlight_matches,
+            dealing: None,
+            new_game_menu: None,
+            notice: None,
+            autosaved: resumed,
         };
+        if game.player.is_some() {
+            // nothing to record while watching a replay
+            game.recorded = true;
+        } else if !resumed {
+            game.record_start();
+        }
+        game.update_clock();
         Ok(game)
     }
 
@@ -370,140 +1496,64 @@ impl<'a> GameBuilder<'a> {
         self
     }
 
+    pub fn font_file<P: AsRef<Path>>(mut self, font_file: P) -> Self {
+        self.font_file = Some(font_file.as_ref().to_path_buf());
+        self
+    }
+
     pub fn map(mut self, map: Map) -> Self {
         self.map = map;
         self
     }
 
-    pub fn background_color(mut self, background_color: &[f32; 3]) -> Self {
-        self.background_color = *background_color;
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
         self
     }
-}
 
-/// Generates random solvable configuration
-fn fill_random_ids(tiles: &mut [Tile], rng: &mut ThreadRng) -> Result<()> {
-    // it is based on the behavior of KMahjongg, not GNOME Mahjongg
-    let pairs: Vec<usize> = std::iter::repeat_with(|| {
-        let mut pairs: Vec<usize> = (0..144 / 2).collect();
-        pairs.shuffle(rng);
-        pairs
-    })
-    .flatten()
-    .take(tiles.len() / 2)
-    .collect();
-
-    // GNOME Mahjongg version is:
-    // let mut pairs: Vec<usize> = (0..tiles.len() / 2).collect();
-    // pairs.shuffle(rng);
-    // but it doesn't support #tiles > 144 and has more biased tile distribution
-
-    let succeeded = fill_random_ids_impl(tiles, &pairs, 0, rng);
-    for tile in tiles {
-        tile.visible = true;
-    }
-
-    if succeeded {
-        Ok(())
-    } else {
-        Err(anyhow!("No solvable configuration"))
+    pub fn difficulty(mut self, difficulty: Difficulty) -> Self {
+        self.difficulty = difficulty;
+        self
     }
-}
 
-fn fill_random_ids_impl(
-    tiles: &mut [Tile],
-    pairs: &[usize],
-    depth: usize,
-    rng: &mut ThreadRng,
-) -> bool {
-    if depth == pairs.len() {
-        return true;
+    /// Continues the game saved on exit instead of dealing a new one
+    ///
+    /// The save is removed once the game ends.
+    pub fn resume(mut self, data: SaveData) -> Self {
+        self.resumed = Some(data);
+        self
     }
 
-    let mut matches = find_all_matches(tiles);
-    if matches.is_empty() {
-        return false;
+    /// Plays back a replay instead of starting a game
+    pub fn playback(mut self, replay: Replay) -> Self {
+        self.playback = Some(replay);
+        self
     }
-    matches.shuffle(rng);
-    for m in matches {
-        tiles[m.0].id = 2 * pairs[depth];
-        tiles[m.0].visible = false;
-        tiles[m.1].id = 2 * pairs[depth] + 1;
-        tiles[m.1].visible = false;
-
-        if fill_random_ids_impl(tiles, pairs, depth + 1, rng) {
-            return true;
-        }
 
-        tiles[m.0].id = 0;
-        tiles[m.0].visible = true;
-        tiles[m.1].id = 0;
-        tiles[m.1].visible = true;
+    pub fn background_color(mut self, background_color: &[f32; 3]) -> Self {
+        self.background_color = *background_color;
+        self
     }
 
-    false
-}
-
-#[derive(Debug, Copy, Clone)]
-struct Match(usize, usize);
-
-impl PartialEq for Match {
-    fn eq(&self, other: &Match) -> bool {
-        (self.0 == other.0 && self.1 == other.1) || (self.0 == other.1 && self.1 == other.0)
+    pub fn backgrounds(mut self, backgrounds: Backgrounds) -> Self {
+        self.backgrounds = backgrounds;
+        self
     }
-}
 
-impl Eq for Match {}
-
-impl std::hash::Hash for Match {
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        if self.0 < self.1 {
-            self.0.hash(state);
-            self.1.hash(state);
-        } else {
-            self.1.hash(state);
-            self.0.hash(state);
-        }
+    pub fn animation_durations(mut self, durations: Durations) -> Self {
+        self.animation_durations = durations;
+        self
     }
-}
 
-fn find_all_matches(tiles: &[Tile]) -> Vec<Match> {
-    let mut set = std::collections::HashSet::new();
-    for i in 0..tiles.len() {
-        if !tile_is_exposed(i, tiles) {
-            continue;
-        }
-        for m in find_matches(i, tiles) {
-            set.insert(m);
-        }
+    /// Draws tiles that cannot be selected darker
+    pub fn dim_blocked(mut self, dim_blocked: bool) -> Self {
+        self.dim_blocked = dim_blocked;
+        self
     }
-    set.drain().collect()
-}
-
-fn find_matches(index: usize, tiles: &[Tile]) -> impl Iterator<Item = Match> + '_ {
-    (0..tiles.len())
-        .filter(move |i| {
-            *i != index
-                && tiles[*i].visible
-                && tiles[*i].matches(&tiles[index])
-                && tile_is_exposed(*i, tiles)
-        })
-        .map(move |i| Match(i, index))
-}
-
-fn render_svg<P: AsRef<Path>>(path: P) -> Result<::image::RgbaImage> {
-    let data = std::fs::read(path)?;
-    let tree = usvg::Tree::from_data(&data, &usvg::Options::default().to_ref())?;
 
-    let size = tree.svg_node().size.to_screen_size();
-    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
-        .ok_or_else(|| anyhow!("Failed to create pixmap"))?;
-
-    resvg::render(&tree, usvg::FitTo::Original, pixmap.as_mut())
-        .ok_or_else(|| anyhow!("Failed to render SVG"))?;
-
-    let buf = ::image::RgbaImage::from_vec(pixmap.width(), pixmap.height(), pixmap.take())
-        .ok_or_else(|| anyhow!("Failed to construct image buffer from rendered SVG"))?;
-
-    Ok(buf)
+    /// Marks tiles matching the selected one
+    pub fn highlight_matches(mut self, highlight_matches: bool) -> Self {
+        self.highlight_matches = highlight_matches;
+        self
+    }
 }
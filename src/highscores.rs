@@ -0,0 +1,136 @@
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of high score file format, incremented on incompatible changes
+const VERSION: u64 = 1;
+
+/// Number of entries kept per map
+const MAX_ENTRIES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub points: i64,
+    pub time: Duration,
+    pub seed: u64,
+}
+
+/// Best results per map name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HighScores {
+    version: u64,
+    maps: BTreeMap<String, Vec<Entry>>,
+}
+
+impl Default for HighScores {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            maps: BTreeMap::new(),
+        }
+    }
+}
+
+impl HighScores {
+    /// Loads high scores, or returns an empty table if the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        storage::read_versioned_or_default(path, VERSION)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write(path, self)
+    }
+
+    /// Records a result
+    ///
+    /// Returns the rank of the entry if it made it into the table.
+    pub fn add(&mut self, map_name: &str, entry: Entry) -> Option<usize> {
+        let entries = self.maps.entry(map_name.to_string()).or_default();
+
+        // higher points first, then shorter time
+        let rank = entries
+            .iter()
+            .position(|e| {
+                (e.points, std::cmp::Reverse(e.time))
+                    < (entry.points, std::cmp::Reverse(entry.time))
+            })
+            .unwrap_or(entries.len());
+        if rank >= MAX_ENTRIES {
+            return None;
+        }
+
+        entries.insert(rank, entry);
+        entries.truncate(MAX_ENTRIES);
+        Some(rank)
+    }
+
+    /// Returns entries of the given map, best first
+    pub fn entries(&self, map_name: &str) -> &[Entry] {
+        self.maps
+            .get(map_name)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Returns names of maps having any entries, in alphabetical order
+    pub fn map_names(&self) -> impl Iterator<Item = &str> {
+        self.maps.keys().map(String::as_str)
+    }
+}
+
+pub fn file() -> Option<PathBuf> {
+    crate::data_dir().map(|dir| dir.join("highscores.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(points: i64, secs: u64) -> Entry {
+        Entry {
+            points,
+            time: Duration::from_secs(secs),
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn add_orders_by_points_then_time() {
+        let mut scores = HighScores::default();
+        assert_eq!(scores.add("map", entry(100, 60)), Some(0));
+        assert_eq!(scores.add("map", entry(200, 90)), Some(0));
+        assert_eq!(scores.add("map", entry(100, 30)), Some(1));
+        // a tie ranks below the earlier entry
+        assert_eq!(scores.add("map", entry(100, 30)), Some(2));
+
+        assert_eq!(
+            scores.entries("map"),
+            &[
+                entry(200, 90),
+                entry(100, 30),
+                entry(100, 30),
+                entry(100, 60)
+            ]
+        );
+        assert!(scores.entries("other").is_empty());
+    }
+
+    #[test]
+    fn add_keeps_only_best_entries() {
+        let mut scores = HighScores::default();
+        for points in 0..MAX_ENTRIES as i64 {
+            scores.add("map", entry(points, 60));
+        }
+
+        assert_eq!(scores.add("map", entry(-1, 60)), None);
+        assert_eq!(scores.add("map", entry(5, 10)), Some(4));
+
+        let entries = scores.entries("map");
+        assert_eq!(entries.len(), MAX_ENTRIES);
+        assert_eq!(entries[0], entry(MAX_ENTRIES as i64 - 1, 60));
+        assert_eq!(entries[MAX_ENTRIES - 1], entry(1, 60));
+    }
+}
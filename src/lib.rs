@@ -0,0 +1,20 @@
+pub mod board;
+pub mod clock;
+pub mod dealer;
+mod exposure;
+pub mod highscores;
+pub mod map;
+pub mod rating;
+pub mod replay;
+pub mod save;
+pub mod score;
+pub mod solver;
+pub mod stats;
+mod storage;
+
+use std::path::PathBuf;
+
+/// Directory for storing saved games and other records
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")))
+}
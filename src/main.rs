@@ -1,11 +1,28 @@
+mod animation;
+mod background;
+mod dealing;
 mod game;
-mod map;
+mod picker;
+mod tileset;
 
+use animation::Durations;
 use anyhow::{anyhow, Result};
-use game::GameBuilder;
+use background::{Backgrounds, Placement};
+use game::{Cancelled, Exit, Game, GameBuilder};
 use itertools::Itertools;
+use mahjongg::board::State;
+use mahjongg::clock;
+use mahjongg::dealer::Difficulty;
+use mahjongg::highscores::{self, HighScores};
+use mahjongg::map::{self, Map};
+use mahjongg::rating;
+use mahjongg::replay::Replay;
+use mahjongg::save::{self, SaveData};
+use mahjongg::stats::{self, Outcome, Statistics};
+use picker::MapPicker;
 use piston_window::*;
 use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -25,7 +42,7 @@ struct Opt {
     #[structopt(short, long, default_value = "600")]
     height: u32,
 
-    /// Theme file (GNOME Mahjongg format)
+    /// Theme file (GNOME Mahjongg theme or KMahjongg tileset .desktop file)
     #[structopt(
         short,
         long,
@@ -33,6 +50,14 @@ struct Opt {
     )]
     theme: PathBuf,
 
+    /// Font file used for messages
+    #[structopt(
+        short,
+        long,
+        default_value = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf"
+    )]
+    font: PathBuf,
+
     /// Map files or directories containing map files (GNOME Mahjongg or KMahjongg format)
     #[structopt(short, long, default_value = "/usr/share/gnome-mahjongg/maps/")]
     map: Vec<PathBuf>,
@@ -40,54 +65,444 @@ struct Opt {
     /// Background color
     #[structopt(short, long, default_value = "#34385b")]
     background: String,
+
+    /// Background images or directories containing them (KMahjongg .desktop files, SVG or raster images), cycled with B
+    #[structopt(long, value_name = "file")]
+    background_image: Vec<PathBuf>,
+
+    /// How background images cover the window: stretch, tile or center (cycled with Shift+B)
+    #[structopt(long, value_name = "placement", default_value = "stretch")]
+    background_placement: Placement,
+
+    /// Seed for dealing tiles (random if not specified)
+    #[structopt(short, long)]
+    seed: Option<u64>,
+
+    /// Difficulty of deals: easy, medium or hard
+    #[structopt(long, value_name = "difficulty", default_value = "medium")]
+    difficulty: Difficulty,
+
+    /// Print high scores and exit (only those of the given seed if --seed is specified)
+    #[structopt(long)]
+    scores: bool,
+
+    /// Duration of fading removed tiles out and undone tiles in, in milliseconds
+    #[structopt(long, value_name = "ms", default_value = "250")]
+    fade_duration: u64,
+
+    /// Duration of shaking a tile that cannot be selected, in milliseconds
+    #[structopt(long, value_name = "ms", default_value = "300")]
+    shake_duration: u64,
+
+    /// Disable animations
+    #[structopt(long)]
+    no_animations: bool,
+
+    /// Draw tiles that cannot be selected darker (toggled with D)
+    #[structopt(long)]
+    dim_blocked: bool,
+
+    /// Mark tiles matching the selected one (toggled with P)
+    #[structopt(long)]
+    highlight_matches: bool,
+
+    /// Play back a recorded game instead of playing
+    #[structopt(long, value_name = "file")]
+    replay: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Print play statistics of each map
+    Stats {
+        /// Print as JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
 
+    if opt.scores {
+        return print_high_scores(opt.seed);
+    }
+    if let Some(Command::Stats { json }) = opt.command {
+        return print_stats(json);
+    }
+
     let background_color = parse_color(&opt.background)?;
 
     anyhow::ensure!(opt.theme.exists(), "Theme file not found");
 
-    let map = {
-        let mut maps = map::load_from_paths(&opt.map);
+    let animation_durations = if opt.no_animations {
+        Durations::disabled()
+    } else {
+        Durations {
+            fade: Duration::from_millis(opt.fade_duration),
+            shake: Duration::from_millis(opt.shake_duration),
+        }
+    };
+
+    if let Some(file) = &opt.replay {
+        let replay = Replay::load(file)?;
+        let mut window = create_window(&opt)?;
+        let backgrounds = load_backgrounds(&mut window, &opt);
+        let mut game = game_builder(
+            &mut window,
+            &opt,
+            &background_color,
+            animation_durations,
+            backgrounds,
+        )
+        .playback(replay)
+        .build()?;
+        game.run(&mut window);
+        return Ok(());
+    }
+
+    let maps = map::load_from_paths(&opt.map);
+    if maps.len() == 1 {
+        eprintln!("Failed to load any maps. Will default to built-in layout.");
+    }
 
-        match maps.len() {
-            0 => unreachable!(),
-            1 => {
-                eprintln!("Failed to load any maps. Will default to built-in layout.");
-                maps.swap_remove(0)
+    let map = if let Some(map) = select_map(&maps)? {
+        map
+    } else {
+        return Ok(());
+    };
+
+    // an explicitly given seed takes precedence over the saved game,
+    // and resuming is asked in the terminal, so only before the window opens
+    let resumed = if opt.seed.is_none() {
+        offer_resume(&map)?
+    } else {
+        None
+    };
+
+    let mut window = create_window(&opt)?;
+    let backgrounds = load_backgrounds(&mut window, &opt);
+    let builder = game_builder(
+        &mut window,
+        &opt,
+        &background_color,
+        animation_durations,
+        backgrounds,
+    );
+    let builder = if let Some(data) = resumed {
+        builder.resume(data)
+    } else {
+        let seed = opt.seed.unwrap_or_else(rand::random);
+        println!("Seed: {}", seed);
+
+        builder.map(map).seed(seed).difficulty(opt.difficulty)
+    };
+    let mut game = match builder.build() {
+        Ok(game) => game,
+        Err(err) if err.is::<Cancelled>() => return Ok(()),
+        Err(err) => return Err(err),
+    };
+
+    let mut picker: Option<MapPicker> = None;
+    loop {
+        match game.run(&mut window) {
+            Exit::Quit => {
+                autosave(&game);
+                return Ok(());
             }
-            _ => {
-                use dialoguer::theme::ColorfulTheme;
-                use dialoguer::Select;
-
-                if let Some(selected) = Select::with_theme(&ColorfulTheme::default())
-                    .items(&maps.iter().map(|map| &map.name).collect::<Vec<_>>())
-                    .default(0)
-                    .paged(true)
-                    .interact_opt()?
-                {
-                    maps.swap_remove(selected)
+            Exit::ChangeMap => {
+                let picker = match &mut picker {
+                    Some(picker) => picker,
+                    None => match MapPicker::new(
+                        &mut window,
+                        &maps,
+                        &opt.theme,
+                        &opt.font,
+                        &background_color,
+                    ) {
+                        Ok(new_picker) => picker.insert(new_picker),
+                        Err(err) => {
+                            // the game goes on rather than being lost
+                            eprintln!("Failed to show maps: {}", err);
+                            continue;
+                        }
+                    },
+                };
+
+                // going back from the list continues the game
+                let map = if let Some(map) = picker.run(&mut window, game.map()) {
+                    map
                 } else {
-                    return Ok(());
+                    continue;
+                };
+
+                let seed = rand::random();
+                println!("Seed: {}", seed);
+                // keep the background chosen in the game
+                let built = game_builder(
+                    &mut window,
+                    &opt,
+                    &background_color,
+                    animation_durations,
+                    game.backgrounds().clone(),
+                )
+                .map(map)
+                .seed(seed)
+                .difficulty(opt.difficulty)
+                .build();
+                match built {
+                    Ok(new_game) => {
+                        game.abandon();
+                        game = new_game;
+                    }
+                    // the previous game goes on if the new one could not be dealt
+                    Err(err) if err.is::<Cancelled>() => (),
+                    Err(err) => eprintln!("Failed to start a game: {}", err),
                 }
             }
         }
+    }
+}
+
+/// Returns a builder with the look and feel chosen on command line
+fn game_builder<'a>(
+    window: &'a mut PistonWindow,
+    opt: &Opt,
+    background_color: &[f32; 3],
+    animation_durations: Durations,
+    backgrounds: Backgrounds,
+) -> GameBuilder<'a> {
+    GameBuilder::new(window)
+        .theme_file(&opt.theme)
+        .font_file(&opt.font)
+        .background_color(background_color)
+        .backgrounds(backgrounds)
+        .animation_durations(animation_durations)
+        .dim_blocked(opt.dim_blocked)
+        .highlight_matches(opt.highlight_matches)
+}
+
+fn create_window(opt: &Opt) -> Result<PistonWindow> {
+    let mut window: PistonWindow =
+        WindowSettings::new(env!("CARGO_PKG_NAME"), [opt.width, opt.height])
+            .build()
+            .map_err(|_| anyhow!("Failed to create window"))?;
+    // redraw continuously to keep the clock ticking, smoothly enough for animations
+    window.set_max_fps(if opt.no_animations { 30 } else { 60 });
+    Ok(window)
+}
+
+fn load_backgrounds(window: &mut PistonWindow, opt: &Opt) -> Backgrounds {
+    let images = background::load_from_paths(window, &opt.background_image);
+    Backgrounds {
+        current: if images.is_empty() { None } else { Some(0) },
+        images,
+        placement: opt.background_placement,
+    }
+}
+
+/// Offers resuming the game saved on last exit if it was played on the given map
+fn offer_resume(map: &Map) -> Result<Option<SaveData>> {
+    use dialoguer::theme::ColorfulTheme;
+    use dialoguer::Confirm;
+
+    let data = match save::autosave_file(&map.name).map(SaveData::load) {
+        Some(Ok(data)) if data.board.map() == map => data,
+        _ => return Ok(None),
     };
 
-    let mut window: PistonWindow = WindowSettings::new(&map.name, [opt.width, opt.height])
-        .build()
-        .map_err(|_| anyhow!("Failed to create window"))?;
-    window.set_lazy(true);
+    let resume = Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt("Resume the game saved on last exit?")
+        .default(true)
+        .interact()?;
 
-    let mut game = GameBuilder::new(&mut window)
-        .theme_file(opt.theme)
-        .map(map)
-        .background_color(&background_color)
-        .build()?;
+    // a resumed game keeps its save until it ends, in case it is not saved again
+    if resume {
+        return Ok(Some(data));
+    }
+
+    end_saved_game(&data);
+    if let Err(err) = save::remove_autosave(&map.name) {
+        eprintln!("Failed to remove saved game: {}", err);
+    }
+
+    Ok(None)
+}
+
+/// Records a saved game as given up, as it will not be continued
+fn end_saved_game(data: &SaveData) {
+    let outcome = if data.board.is_stuck() {
+        Outcome::Stuck
+    } else {
+        Outcome::Abandoned
+    };
+    let result = stats::update(|stats| {
+        stats.record_end(
+            &data.board.map().name,
+            outcome,
+            &data.score,
+            data.elapsed,
+            data.replay
+                .as_ref()
+                .and_then(|replay| replay.rating.as_ref()),
+        )
+    });
+    if let Err(err) = result {
+        eprintln!("Failed to update statistics: {}", err);
+    }
+    if let Some(replay) = &data.replay {
+        game::save_replay(replay);
+    }
+}
+
+/// Saves unfinished game so that it can be resumed on next launch
+fn autosave(game: &Game) {
+    // a won game has nothing to continue, and its save has been removed when it was won
+    if game.state() == State::Won {
+        return;
+    }
+
+    let file = if let Some(file) = save::autosave_file(&game.map().name) {
+        file
+    } else {
+        return;
+    };
+
+    if !game.is_autosaved() {
+        // another game saved earlier on the map is replaced, so it ends here
+        if let Ok(data) = SaveData::load(&file) {
+            end_saved_game(&data);
+        }
+    }
 
-    game.run(&mut window);
+    if let Err(err) = game.save_data().save(file) {
+        eprintln!("Failed to save game: {}", err);
+    }
+}
+
+fn select_map(maps: &[Map]) -> Result<Option<Map>> {
+    match maps.len() {
+        0 => unreachable!(),
+        1 => Ok(Some(maps[0].clone())),
+        _ => {
+            use dialoguer::theme::ColorfulTheme;
+            use dialoguer::Select;
+
+            let selected = Select::with_theme(&ColorfulTheme::default())
+                .items(&maps.iter().map(|map| &map.name).collect::<Vec<_>>())
+                .default(0)
+                .paged(true)
+                .interact_opt()?;
+            Ok(selected.map(|selected| maps[selected].clone()))
+        }
+    }
+}
+
+fn print_high_scores(seed: Option<u64>) -> Result<()> {
+    let file = highscores::file().ok_or_else(|| anyhow!("No data directory"))?;
+    let high_scores = HighScores::load(file)?;
+
+    for name in high_scores.map_names() {
+        let entries: Vec<_> = high_scores
+            .entries(name)
+            .iter()
+            .filter(|entry| seed.map(|seed| entry.seed == seed).unwrap_or(true))
+            .collect();
+        if entries.is_empty() {
+            continue;
+        }
+
+        println!("{}", name);
+        for (i, entry) in entries.iter().enumerate() {
+            println!(
+                "{:>4}. {:>6} {:>8}  (seed: {})",
+                i + 1,
+                entry.points,
+                clock::format_duration(entry.time),
+                entry.seed
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn print_stats(json: bool) -> Result<()> {
+    let file = stats::file().ok_or_else(|| anyhow!("No data directory"))?;
+    let statistics = Statistics::load(file)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&statistics)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:>7} {:>5} {:>5} {:>9} {:>8} {:>5} {:>5} {:>8} {:>6} {:>4}",
+        "Map",
+        "Started",
+        "Won",
+        "Stuck",
+        "Abandoned",
+        "Avg time",
+        "Hints",
+        "Undos",
+        "Shuffles",
+        "Streak",
+        "Best"
+    );
+    for (name, stats) in statistics.iter() {
+        let average_time = stats
+            .average_win_time()
+            .map(clock::format_duration)
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<24} {:>7} {:>5} {:>5} {:>9} {:>8} {:>5} {:>5} {:>8} {:>6} {:>4}",
+            name,
+            stats.started,
+            stats.won,
+            stats.stuck,
+            stats.abandoned,
+            average_time,
+            stats.hints,
+            stats.undos,
+            stats.shuffles,
+            stats.current_streak,
+            stats.best_streak
+        );
+    }
+
+    // results of comparably hard deals, for maps with rated games
+    let rated: Vec<_> = statistics
+        .iter()
+        .filter(|(_, stats)| !stats.by_rating.is_empty())
+        .collect();
+    if !rated.is_empty() {
+        println!();
+        println!(
+            "{:<24} {:>6} {:>6} {:>5} {:>8}",
+            "Map", "Rating", "Played", "Won", "Avg time"
+        );
+        for (name, stats) in rated {
+            for (band, rating_stats) in &stats.by_rating {
+                let average_time = rating_stats
+                    .average_win_time()
+                    .map(clock::format_duration)
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:<24} {:>6} {:>6} {:>5} {:>8}",
+                    name,
+                    rating::band_name(*band),
+                    rating_stats.played,
+                    rating_stats.won,
+                    average_time
+                );
+            }
+        }
+    }
 
     Ok(())
 }
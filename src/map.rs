@@ -3,21 +3,83 @@ mod gnome_mahjongg;
 mod kmahjongg;
 
 use anyhow::Result;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Slot {
     pub x: isize,
     pub y: isize,
     pub z: isize,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Map {
     pub name: String,
     pub slots: Vec<Slot>,
     pub width: usize,
     pub height: usize,
+    /// File the map was loaded from, None for the built-in map
+    #[serde(default)]
+    pub source: Option<Source>,
+}
+
+impl Map {
+    /// Returns the slots sorted so that drawing them in order overlaps tiles correctly
+    ///
+    /// The order is total, as tile indices and thus seeded deals depend on it.
+    pub fn slots_in_draw_order(&self) -> Vec<Slot> {
+        let mut slots = self.slots.clone();
+        slots.sort_unstable_by(|a, b| {
+            a.z.cmp(&b.z)
+                .then_with(|| (a.y - b.y).cmp(&(a.x - b.x)))
+                .then_with(|| a.x.cmp(&b.x))
+        });
+        slots
+    }
+
+    /// Returns the number of layers, counting from the ground
+    pub fn layers(&self) -> usize {
+        self.slots
+            .iter()
+            .map(|slot| slot.z as usize + 1)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Maps are the same if they have the same name and layout, wherever they were loaded from
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.slots == other.slots
+            && self.width == other.width
+            && self.height == other.height
+    }
+}
+
+impl Eq for Map {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub path: PathBuf,
+    pub format: Format,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Format {
+    GnomeMahjongg,
+    KMahjongg,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::GnomeMahjongg => write!(f, "GNOME Mahjongg"),
+            Format::KMahjongg => write!(f, "KMahjongg"),
+        }
+    }
 }
 
 pub fn load_from_paths<P: AsRef<Path>>(paths: &[P]) -> Vec<Map> {
@@ -49,15 +111,20 @@ pub fn load_maps<P: AsRef<Path>>(path: P) -> Result<Vec<Map>> {
         .and_then(|ext| ext.to_str())
         .map(|ext| ext.to_lowercase());
 
-    if let Some(ext) = ext {
-        match &ext[..] {
-            "map" => return gnome_mahjongg::load(path),
-            "desktop" => return kmahjongg::load(path),
-            _ => (),
-        }
+    let (mut maps, format) = match ext.as_deref() {
+        Some("map") => (gnome_mahjongg::load(&path)?, Format::GnomeMahjongg),
+        Some("desktop") => (kmahjongg::load(&path)?, Format::KMahjongg),
+        _ => return Err(anyhow::anyhow!("Not a map file")),
+    };
+
+    for map in &mut maps {
+        map.source = Some(Source {
+            path: path.as_ref().to_path_buf(),
+            format,
+        });
     }
 
-    Err(anyhow::anyhow!("Not a map file"))
+    Ok(maps)
 }
 
 fn calc_size(slots: &[Slot]) -> (usize, usize) {
@@ -70,3 +137,17 @@ fn calc_size(slots: &[Slot]) -> (usize, usize) {
 
     (width as usize + 2, height as usize + 2)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_order_does_not_depend_on_slot_order() {
+        let map = default::EASY.clone();
+        let mut reversed = map.clone();
+        reversed.slots.reverse();
+
+        assert_eq!(map.slots_in_draw_order(), reversed.slots_in_draw_order());
+    }
+}
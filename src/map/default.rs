@@ -163,6 +163,7 @@ lazy_static! {
             slots,
             width,
             height,
+            source: None,
         }
     };
 }
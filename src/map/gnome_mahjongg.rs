@@ -26,6 +26,7 @@ pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Map>> {
             slots,
             width,
             height,
+            source: None,
         });
     }
 
@@ -15,6 +15,7 @@ pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Map>> {
         slots,
         width,
         height,
+        source: None,
     };
     Ok(vec![map])
 }
@@ -0,0 +1,372 @@
+use crate::game::{self, Geometry};
+use crate::tileset::Tileset;
+use anyhow::{anyhow, Result};
+use mahjongg::map::{Map, Slot};
+use piston_window::*;
+use std::path::Path;
+
+const ROW_HEIGHT: f64 = 96.0;
+const THUMBNAIL_SIZE: [f64; 2] = [128.0, 80.0];
+/// Space above the list, taken by the heading
+const LIST_TOP: f64 = 72.0;
+/// Space below the list, taken by the help line
+const LIST_BOTTOM: f64 = 48.0;
+const MARGIN: f64 = 24.0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum SortOrder {
+    /// Order in which the maps were loaded
+    Loaded,
+    /// Fewest tiles first
+    Size,
+}
+
+/// In-window list for choosing a map
+pub struct MapPicker<'a> {
+    maps: &'a [Map],
+    /// Slots of each map in draw order, for thumbnails
+    slots: Vec<Vec<Slot>>,
+    glyphs: Glyphs,
+    tileset: Tileset,
+    background_color: [f32; 4],
+    /// Only maps whose name contains this are listed
+    filter: String,
+    sort_order: SortOrder,
+    /// Indices of listed maps
+    shown: Vec<usize>,
+    /// Index into `shown`
+    selected: usize,
+    /// Index of the topmost row shown
+    scroll: usize,
+    cursor_pos: Option<[f64; 2]>,
+}
+
+impl<'a> MapPicker<'a> {
+    pub fn new<P: AsRef<Path>, Q: AsRef<Path>>(
+        window: &mut PistonWindow,
+        maps: &'a [Map],
+        theme_file: P,
+        font_file: Q,
+        background_color: &[f32; 3],
+    ) -> Result<Self> {
+        let glyphs = window
+            .load_font(font_file.as_ref())
+            .map_err(|_| anyhow!("Failed to load font"))?;
+        let tileset = Tileset::load(window, theme_file)?;
+
+        let mut picker = Self {
+            maps,
+            slots: maps.iter().map(Map::slots_in_draw_order).collect(),
+            glyphs,
+            tileset,
+            background_color: [
+                background_color[0],
+                background_color[1],
+                background_color[2],
+                1.0,
+            ],
+            filter: String::new(),
+            sort_order: SortOrder::Loaded,
+            shown: Vec::new(),
+            selected: 0,
+            scroll: 0,
+            cursor_pos: None,
+        };
+        picker.update_shown();
+        Ok(picker)
+    }
+
+    /// Shows the list until a map is chosen, returning None if cancelled
+    pub fn run(&mut self, window: &mut PistonWindow, current: &Map) -> Option<Map> {
+        window.set_title(format!("{} - Choose a map", env!("CARGO_PKG_NAME")));
+        self.select_map(current);
+        // text of the key that opened the list arrives before the first frame
+        let mut rendered = false;
+
+        while let Some(event) = window.next() {
+            let rows = visible_rows(window.draw_size());
+            self.scroll_to_selected(rows);
+
+            if event.render_args().is_some() {
+                self.draw(window, &event, rows);
+                rendered = true;
+            }
+
+            if let Some(pos) = event.mouse_cursor_args() {
+                self.cursor_pos = Some(pos);
+                if let Some(pointed) = self.get_pointed_row(rows) {
+                    self.selected = pointed;
+                }
+            }
+
+            if let Some([_, dy]) = event.mouse_scroll_args() {
+                if dy > 0.0 {
+                    self.move_selection(-1);
+                } else if dy < 0.0 {
+                    self.move_selection(1);
+                }
+            }
+
+            if let Some(text) = event.text_args().filter(|_| rendered) {
+                let text: String = text.chars().filter(|c| !c.is_control()).collect();
+                if !text.is_empty() {
+                    self.filter.push_str(&text);
+                    self.update_shown();
+                }
+            }
+
+            if let Some(button) = event.press_args() {
+                match button {
+                    Button::Keyboard(Key::Up) => self.move_selection(-1),
+                    Button::Keyboard(Key::Down) => self.move_selection(1),
+                    Button::Keyboard(Key::PageUp) => self.move_selection(-(rows as isize)),
+                    Button::Keyboard(Key::PageDown) => self.move_selection(rows as isize),
+                    Button::Keyboard(Key::Home) => self.selected = 0,
+                    Button::Keyboard(Key::End) => {
+                        self.selected = self.shown.len().saturating_sub(1)
+                    }
+                    Button::Keyboard(Key::Backspace) => {
+                        self.filter.pop();
+                        self.update_shown();
+                    }
+                    Button::Keyboard(Key::Tab) => {
+                        self.sort_order = match self.sort_order {
+                            SortOrder::Loaded => SortOrder::Size,
+                            SortOrder::Size => SortOrder::Loaded,
+                        };
+                        self.update_shown();
+                    }
+                    Button::Keyboard(Key::Return | Key::NumPadEnter) => {
+                        if let Some(&index) = self.shown.get(self.selected) {
+                            return Some(self.maps[index].clone());
+                        }
+                    }
+                    Button::Mouse(MouseButton::Left) => {
+                        if let Some(pointed) = self.get_pointed_row(rows) {
+                            return Some(self.maps[self.shown[pointed]].clone());
+                        }
+                    }
+                    Button::Keyboard(Key::Escape) => {
+                        if self.filter.is_empty() {
+                            return None;
+                        }
+                        self.filter.clear();
+                        self.update_shown();
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Lists maps matching the filter in the sort order, keeping the selected map if possible
+    fn update_shown(&mut self) {
+        let selected = self.shown.get(self.selected).copied();
+
+        let filter = self.filter.to_lowercase();
+        let maps = self.maps;
+        self.shown = (0..maps.len())
+            .filter(|&i| maps[i].name.to_lowercase().contains(&filter))
+            .collect();
+        if self.sort_order == SortOrder::Size {
+            // stable sort keeps maps of the same size in loaded order
+            self.shown.sort_by_key(|&i| maps[i].slots.len());
+        }
+
+        self.selected = selected
+            .and_then(|selected| self.shown.iter().position(|&i| i == selected))
+            .unwrap_or(0);
+    }
+
+    fn select_map(&mut self, map: &Map) {
+        if let Some(index) = self.shown.iter().position(|&i| &self.maps[i] == map) {
+            self.selected = index;
+        }
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let last = self.shown.len() as isize - 1;
+        self.selected = (self.selected as isize + delta).clamp(0, last.max(0)) as usize;
+    }
+
+    fn scroll_to_selected(&mut self, rows: usize) {
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+    }
+
+    fn get_pointed_row(&self, rows: usize) -> Option<usize> {
+        let cursor_pos = self.cursor_pos?;
+        if cursor_pos[1] < LIST_TOP {
+            return None;
+        }
+
+        let row = ((cursor_pos[1] - LIST_TOP) / ROW_HEIGHT) as usize;
+        let index = self.scroll + row;
+        if row < rows && index < self.shown.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn draw(&mut self, window: &mut PistonWindow, event: &Event, rows: usize) {
+        const WHITE: [f32; 4] = [1.0; 4];
+        const GRAY: [f32; 4] = [0.75, 0.75, 0.75, 1.0];
+
+        let size = window.draw_size();
+        let maps = self.maps;
+        let slots = &self.slots;
+        let shown = &self.shown;
+        let selected = self.selected;
+        let scroll = self.scroll;
+        let background_color = self.background_color;
+        let tileset = &self.tileset;
+        let glyphs = &mut self.glyphs;
+
+        let heading = match self.sort_order {
+            SortOrder::Loaded => "Choose a map",
+            SortOrder::Size => "Choose a map (by size)",
+        };
+        let filter = if self.filter.is_empty() {
+            "Type to filter".to_string()
+        } else {
+            format!("Filter: {}", self.filter)
+        };
+
+        window.draw_2d(event, |c, g, device| {
+            clear(background_color, g);
+
+            Text::new_color(WHITE, 28)
+                .draw(
+                    heading,
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(MARGIN, 44.0),
+                    g,
+                )
+                .ok();
+            let filter_x = size.width - MARGIN - glyphs.width(18, &filter).unwrap_or(0.0);
+            Text::new_color(GRAY, 18)
+                .draw(
+                    &filter,
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(filter_x, 44.0),
+                    g,
+                )
+                .ok();
+
+            if shown.is_empty() {
+                Text::new_color(GRAY, 18)
+                    .draw(
+                        "No maps match the filter",
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(MARGIN, LIST_TOP + 32.0),
+                        g,
+                    )
+                    .ok();
+            }
+
+            for (row, &index) in shown.iter().enumerate().skip(scroll).take(rows) {
+                let map = &maps[index];
+                let y = LIST_TOP + (row - scroll) as f64 * ROW_HEIGHT;
+                if row == selected {
+                    rectangle(
+                        [1.0, 1.0, 1.0, 0.2],
+                        [MARGIN / 2.0, y, size.width - MARGIN, ROW_HEIGHT],
+                        c.transform,
+                        g,
+                    );
+                }
+
+                let area = [
+                    MARGIN,
+                    y + (ROW_HEIGHT - THUMBNAIL_SIZE[1]) / 2.0,
+                    THUMBNAIL_SIZE[0],
+                    THUMBNAIL_SIZE[1],
+                ];
+                draw_thumbnail(map, &slots[index], tileset, area, &c, g);
+
+                let text_x = MARGIN * 2.0 + THUMBNAIL_SIZE[0];
+                Text::new_color(WHITE, 20)
+                    .draw(
+                        &map.name,
+                        glyphs,
+                        &c.draw_state,
+                        c.transform.trans(text_x, y + 34.0),
+                        g,
+                    )
+                    .ok();
+                for (i, line) in info_lines(map).iter().enumerate() {
+                    Text::new_color(GRAY, 14)
+                        .draw(
+                            line,
+                            glyphs,
+                            &c.draw_state,
+                            c.transform.trans(text_x, y + 58.0 + i as f64 * 20.0),
+                            g,
+                        )
+                        .ok();
+                }
+            }
+
+            Text::new_color(WHITE, 14)
+                .draw(
+                    "Up/Down: move    Tab: sort by size    Enter or click: play    Esc: back",
+                    glyphs,
+                    &c.draw_state,
+                    c.transform.trans(MARGIN, size.height - 18.0),
+                    g,
+                )
+                .ok();
+
+            glyphs.factory.encoder.flush(device);
+        });
+    }
+}
+
+/// Draws the layout of a map with arbitrary tiles
+fn draw_thumbnail(
+    map: &Map,
+    slots: &[Slot],
+    tileset: &Tileset,
+    area: [f64; 4],
+    c: &Context,
+    g: &mut G2d,
+) {
+    let geometry = Geometry::new(map, tileset, area);
+    for (i, slot) in slots.iter().enumerate() {
+        let pos = geometry.calc_tile_pos(slot);
+        // vary the tiles so that the thumbnail looks like a deal
+        let id = (i * 4) % 144;
+        game::draw_tile(tileset, &geometry, pos, id, false, [1.0; 4], c, g);
+    }
+}
+
+fn info_lines(map: &Map) -> Vec<String> {
+    let source = match &map.source {
+        Some(source) => (source.format.to_string(), source.path.display().to_string()),
+        None => ("Built-in".to_string(), "-".to_string()),
+    };
+
+    vec![
+        format!(
+            "{} tiles    {} layers    {}",
+            map.slots.len(),
+            map.layers(),
+            source.0
+        ),
+        source.1,
+    ]
+}
+
+/// Returns how many rows fit in the window
+fn visible_rows(draw_size: Size) -> usize {
+    (((draw_size.height - LIST_TOP - LIST_BOTTOM) / ROW_HEIGHT) as usize).max(1)
+}
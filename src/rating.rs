@@ -0,0 +1,119 @@
+use crate::board::Board;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// Number of stages of a game over which branching is averaged separately
+const STAGES: usize = 4;
+
+/// Difficulty of a deal, estimated from random playouts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rating {
+    /// Fraction of playouts that got stuck before clearing the board
+    pub stuck_rate: f64,
+    /// Average number of available moves in each quarter of the game
+    pub branching: [f64; STAGES],
+    /// Average length of the longest run of moves without an alternative in a playout
+    pub forced_depth: f64,
+}
+
+impl Rating {
+    /// Returns the difficulty from 0 to 100, the percentage of stuck playouts
+    pub fn score(&self) -> u32 {
+        (self.stuck_rate * 100.0).round() as u32
+    }
+
+    /// Returns the lower end of the range of ten points the score falls in
+    ///
+    /// Deals in the same band are comparably hard.
+    pub fn band(&self) -> u32 {
+        (self.score() / 10 * 10).min(90)
+    }
+}
+
+/// Returns the range of scores in a band, such as "40-49"
+pub fn band_name(band: u32) -> String {
+    let last = if band >= 90 { 100 } else { band + 9 };
+    format!("{}-{}", band, last)
+}
+
+/// Rates deals by playing random moves until the board is cleared or stuck
+#[derive(Debug, Clone)]
+pub struct Rater {
+    playouts: usize,
+}
+
+impl Default for Rater {
+    fn default() -> Self {
+        Self { playouts: 100 }
+    }
+}
+
+impl Rater {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn playouts(mut self, playouts: usize) -> Self {
+        self.playouts = playouts;
+        self
+    }
+
+    /// Rates the position, always the same way for the same board
+    pub fn rate(&self, board: &Board) -> Rating {
+        let mut rng = ChaCha8Rng::seed_from_u64(board.seed());
+        let pairs = board.tiles().iter().filter(|tile| tile.visible).count() / 2;
+
+        let mut stuck = 0;
+        let mut moves = [0; STAGES];
+        let mut positions = [0; STAGES];
+        let mut forced_depth = 0;
+
+        for _ in 0..self.playouts {
+            let mut board = board.clone();
+            let mut forced = 0;
+            let mut longest_forced = 0;
+
+            for i in 0.. {
+                let matches = board.find_all_matches();
+                let m = if let Some(m) = matches.choose(&mut rng) {
+                    *m
+                } else {
+                    break;
+                };
+
+                let stage = (i * STAGES / pairs.max(1)).min(STAGES - 1);
+                moves[stage] += matches.len();
+                positions[stage] += 1;
+
+                if matches.len() == 1 {
+                    forced += 1;
+                    longest_forced = longest_forced.max(forced);
+                } else {
+                    forced = 0;
+                }
+
+                board.remove(m);
+            }
+
+            if !board.is_cleared() {
+                stuck += 1;
+            }
+            forced_depth += longest_forced;
+        }
+
+        let playouts = self.playouts.max(1) as f64;
+        let mut branching = [0.0; STAGES];
+        for (stage, branching) in branching.iter_mut().enumerate() {
+            if positions[stage] > 0 {
+                *branching = moves[stage] as f64 / positions[stage] as f64;
+            }
+        }
+
+        Rating {
+            stuck_rate: stuck as f64 / playouts,
+            branching,
+            forced_depth: forced_depth as f64 / playouts,
+        }
+    }
+}
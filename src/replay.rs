@@ -0,0 +1,317 @@
+use crate::board::{Board, Match, Move};
+use crate::dealer::{Dealer, Difficulty};
+use crate::map::Map;
+use crate::rating::Rating;
+use crate::storage;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Version of replay file format, incremented on incompatible changes
+const VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Action {
+    Remove(Match),
+    Undo,
+    Redo,
+    /// Ids of all tiles after shuffling
+    Shuffle(Vec<usize>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Play time at which the action was taken
+    pub time: Duration,
+    pub action: Action,
+}
+
+/// Record of a game from the initial deal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    version: u64,
+    pub map: Map,
+    pub seed: u64,
+    #[serde(default)]
+    pub difficulty: Difficulty,
+    /// Rating of the initial deal
+    #[serde(default)]
+    pub rating: Option<Rating>,
+    pub events: Vec<Event>,
+}
+
+impl Replay {
+    pub fn new(map: Map, seed: u64, difficulty: Difficulty) -> Self {
+        Self {
+            version: VERSION,
+            map,
+            seed,
+            difficulty,
+            rating: None,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        storage::read_versioned(path, VERSION)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write(path, self)
+    }
+
+    pub fn push(&mut self, time: Duration, action: Action) {
+        self.events.push(Event { time, action });
+    }
+
+    /// Records the last move performed on the board
+    pub fn push_last_move(&mut self, time: Duration, board: &Board) {
+        match board.history().last() {
+            Some(Move::Remove(m)) => self.push(time, Action::Remove(*m)),
+            Some(Move::Shuffle { after, .. }) => self.push(time, Action::Shuffle(after.clone())),
+            None => (),
+        }
+    }
+
+    /// Deals the initial board
+    pub fn initial_board(&self) -> Result<Board> {
+        Dealer::new()
+            .difficulty(self.difficulty)
+            .deal(self.map.clone(), self.seed, &mut |_| ())
+    }
+
+    /// Performs the given event on the board
+    pub fn apply(board: &mut Board, event: &Event) -> Result<()> {
+        let succeeded = match &event.action {
+            Action::Remove(m) => {
+                if board.can_remove(*m) {
+                    board.remove(*m);
+                    true
+                } else {
+                    false
+                }
+            }
+            Action::Undo => board.undo().is_some(),
+            Action::Redo => board.redo().is_some(),
+            Action::Shuffle(ids) => board.shuffle_to(ids.clone()).is_ok(),
+        };
+
+        if succeeded {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Invalid replay: {:?} cannot be performed",
+                event.action
+            ))
+        }
+    }
+
+    /// Reconstructs the board after the given number of events
+    pub fn board_at(&self, initial: &Board, position: usize) -> Result<Board> {
+        let mut board = initial.clone();
+        for event in &self.events[..position] {
+            Self::apply(&mut board, event)?;
+        }
+        Ok(board)
+    }
+
+    /// Checks that every event can be performed
+    pub fn validate(&self, initial: &Board) -> Result<()> {
+        self.board_at(initial, self.events.len()).map(|_| ())
+    }
+}
+
+/// Returns a new file name in the replay directory
+pub fn new_file() -> Option<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default();
+    crate::data_dir().map(|dir| dir.join("replays").join(format!("{}.json", timestamp)))
+}
+
+/// Playback of a replay at adjustable speed
+#[derive(Debug, Clone)]
+pub struct Player {
+    replay: Replay,
+    initial: Board,
+    board: Board,
+    /// Number of events performed
+    position: usize,
+    /// Current time in the replay
+    time: Duration,
+    paused: bool,
+    speed: f64,
+}
+
+impl Player {
+    const MIN_SPEED: f64 = 0.25;
+    const MAX_SPEED: f64 = 16.0;
+
+    pub fn new(replay: Replay) -> Result<Self> {
+        let initial = replay.initial_board()?;
+        replay.validate(&initial)?;
+
+        Ok(Self {
+            replay,
+            board: initial.clone(),
+            initial,
+            position: 0,
+            time: Duration::ZERO,
+            paused: false,
+            speed: 1.0,
+        })
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    pub fn board(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Advances the replay clock, performing events whose time has come
+    ///
+    /// Returns true if the board changed.
+    pub fn advance(&mut self, dt: Duration) -> bool {
+        if self.paused {
+            return false;
+        }
+
+        self.time += dt.mul_f64(self.speed);
+
+        let mut changed = false;
+        while let Some(event) = self.replay.events.get(self.position) {
+            if event.time > self.time {
+                break;
+            }
+            self.perform_next();
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn step_forward(&mut self) -> bool {
+        if self.position >= self.replay.events.len() {
+            return false;
+        }
+
+        self.perform_next();
+        self.time = self.replay.events[self.position - 1].time;
+        true
+    }
+
+    pub fn step_back(&mut self) -> bool {
+        if self.position == 0 {
+            return false;
+        }
+
+        self.seek(self.position - 1);
+        true
+    }
+
+    /// Jumps to the state after the given number of events
+    pub fn seek(&mut self, position: usize) {
+        let position = position.min(self.replay.events.len());
+
+        self.board = self
+            .replay
+            .board_at(&self.initial, position)
+            .expect("replay has been validated");
+        self.position = position;
+        self.time = if position == 0 {
+            Duration::ZERO
+        } else {
+            self.replay.events[position - 1].time
+        };
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(Self::MAX_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(Self::MIN_SPEED);
+    }
+
+    fn perform_next(&mut self) {
+        let event = &self.replay.events[self.position];
+        Replay::apply(&mut self.board, event).expect("replay has been validated");
+        self.position += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map;
+
+    fn replay(actions: Vec<Action>) -> Replay {
+        let mut replay = Replay::new(map::default::EASY.clone(), 1, Difficulty::Medium);
+        for action in actions {
+            replay.push(Duration::from_secs(1), action);
+        }
+        replay
+    }
+
+    #[test]
+    fn validate_accepts_legal_events() {
+        let initial = replay(Vec::new()).initial_board().unwrap();
+        let m = initial.find_all_matches()[0];
+        let mut shuffled: Vec<_> = initial.tiles().iter().map(|tile| tile.id).collect();
+        shuffled.swap(0, 1);
+
+        let replay = replay(vec![
+            Action::Remove(m),
+            Action::Undo,
+            Action::Redo,
+            Action::Shuffle(shuffled),
+        ]);
+        assert!(replay.validate(&initial).is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_illegal_events() {
+        let initial = replay(Vec::new()).initial_board().unwrap();
+        let tiles = initial.tiles();
+        let blocked = (0..tiles.len())
+            .flat_map(|a| (0..a).map(move |b| Match(a, b)))
+            .find(|m| tiles[m.0].matches(&tiles[m.1]) && !initial.is_exposed(m.0))
+            .unwrap();
+        let m = initial.find_all_matches()[0];
+        let mut changed: Vec<_> = tiles.iter().map(|tile| tile.id).collect();
+        changed[0] = changed[1];
+
+        for actions in [
+            vec![Action::Remove(blocked)],
+            vec![Action::Remove(m), Action::Remove(m)],
+            vec![Action::Undo],
+            vec![Action::Remove(m), Action::Redo],
+            vec![Action::Shuffle(changed)],
+        ] {
+            assert!(
+                replay(actions.clone()).validate(&initial).is_err(),
+                "{:?}",
+                actions
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::board::Board;
+use crate::replay::Replay;
+use crate::score::Score;
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of save file format, incremented on incompatible changes
+const VERSION: u64 = 1;
+
+/// Snapshot of a game in progress
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SaveData {
+    version: u64,
+    pub board: Board,
+    pub elapsed: Duration,
+    #[serde(default)]
+    pub score: Score,
+    #[serde(default)]
+    pub replay: Option<Replay>,
+}
+
+impl SaveData {
+    pub fn new(board: Board, elapsed: Duration, score: Score, replay: Option<Replay>) -> Self {
+        Self {
+            version: VERSION,
+            board,
+            elapsed,
+            score,
+            replay,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        storage::read_versioned(path, VERSION)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write(path, self)
+    }
+}
+
+/// File for saving and loading manually
+pub fn save_file() -> Option<PathBuf> {
+    crate::data_dir().map(|dir| dir.join("save.json"))
+}
+
+/// File for saving the game on a map automatically on exit, one per map
+pub fn autosave_file(map_name: &str) -> Option<PathBuf> {
+    // map names may contain characters not allowed in file names
+    let name: String = map_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == ' ' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    crate::data_dir().map(|dir| dir.join("autosave").join(format!("{}.json", name)))
+}
+
+/// Removes the game saved on exit on a map, if any, once it cannot be continued
+pub fn remove_autosave(map_name: &str) -> Result<()> {
+    let file = if let Some(file) = autosave_file(map_name) {
+        file
+    } else {
+        return Ok(());
+    };
+
+    match std::fs::remove_file(file) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
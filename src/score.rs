@@ -0,0 +1,42 @@
+use crate::board::{Board, Move};
+use serde::{Deserialize, Serialize};
+
+const POINTS_PER_PAIR: i64 = 10;
+const UNDO_PENALTY: i64 = 5;
+const HINT_PENALTY: i64 = 15;
+const SHUFFLE_PENALTY: i64 = 30;
+/// Awarded for clearing the board, decreasing by one point per second of play
+const MAX_TIME_BONUS: i64 = 1000;
+
+/// Counts of actions that cost points
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Score {
+    pub undos: usize,
+    pub hints: usize,
+    pub shuffles: usize,
+}
+
+impl Score {
+    /// Computes points for the current position
+    ///
+    /// Only pairs currently removed count, so undoing and redoing a move does not earn anything.
+    pub fn points(&self, board: &Board, elapsed_secs: u64) -> i64 {
+        let pairs = board
+            .history()
+            .iter()
+            .filter(|m| matches!(m, Move::Remove(_)))
+            .count() as i64;
+
+        let time_bonus = if board.is_cleared() {
+            (MAX_TIME_BONUS - elapsed_secs as i64).max(0)
+        } else {
+            0
+        };
+
+        let penalty = self.undos as i64 * UNDO_PENALTY
+            + self.hints as i64 * HINT_PENALTY
+            + self.shuffles as i64 * SHUFFLE_PENALTY;
+
+        (pairs * POINTS_PER_PAIR + time_bonus - penalty).max(0)
+    }
+}
@@ -0,0 +1,281 @@
+use crate::board::{self, Board, Match, Tile};
+use crate::exposure::{Exposure, Neighbors};
+use crate::map::Slot;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Result of solving a position
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Solution {
+    /// Sequence of moves that clears the board
+    Solved(Vec<Match>),
+    /// Every line of play leads to a dead end
+    Unsolvable,
+    /// Budget ran out before a conclusion was reached
+    Aborted,
+}
+
+/// Exhaustive solver for an arbitrary board position
+#[derive(Debug, Clone, Default)]
+pub struct Solver {
+    max_nodes: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+impl Solver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the number of positions visited
+    pub fn max_nodes(mut self, max_nodes: usize) -> Self {
+        self.max_nodes = Some(max_nodes);
+        self
+    }
+
+    /// Limits the wall-clock time spent on searching
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn solve(&self, board: &Board) -> Solution {
+        self.solve_tiles(board.tiles(), board.exposure().neighbors())
+    }
+
+    /// Returns all available moves, those keeping the position solvable first
+    ///
+    /// The timeout applies to the whole ranking, shared evenly among the moves not yet ranked,
+    /// while the node limit applies to each move separately.
+    pub fn rank_moves(&self, board: &Board) -> Vec<Match> {
+        let mut tiles = board.tiles().to_vec();
+        let mut moves = board.find_all_matches();
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let count = moves.len();
+        let mut ranked = 0;
+        moves.sort_by_cached_key(|m| {
+            let move_deadline = deadline.map(|deadline| {
+                let now = Instant::now();
+                now + deadline.saturating_duration_since(now) / (count - ranked) as u32
+            });
+            ranked += 1;
+
+            tiles[m.0].visible = false;
+            tiles[m.1].visible = false;
+            let rank = match self.search(&tiles, board.exposure().neighbors(), move_deadline) {
+                Solution::Solved(_) => 0,
+                Solution::Aborted => 1,
+                Solution::Unsolvable => 2,
+            };
+            tiles[m.0].visible = true;
+            tiles[m.1].visible = true;
+            rank
+        });
+        moves
+    }
+
+    pub(crate) fn solve_tiles(&self, tiles: &[Tile], neighbors: &Arc<Neighbors>) -> Solution {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.search(tiles, neighbors, deadline)
+    }
+
+    fn search(
+        &self,
+        tiles: &[Tile],
+        neighbors: &Arc<Neighbors>,
+        deadline: Option<Instant>,
+    ) -> Solution {
+        let mut search = Search {
+            tiles: tiles.to_vec(),
+            exposure: Exposure::new(neighbors.clone(), tiles),
+            dead_ends: HashSet::new(),
+            moves: Vec::new(),
+            nodes: 0,
+            max_nodes: self.max_nodes,
+            deadline,
+            aborted: false,
+        };
+
+        if search.search() {
+            Solution::Solved(search.moves)
+        } else if search.aborted {
+            Solution::Aborted
+        } else {
+            Solution::Unsolvable
+        }
+    }
+}
+
+struct Search {
+    tiles: Vec<Tile>,
+    exposure: Exposure,
+    /// Visible-tile bitsets of positions known to be unsolvable
+    dead_ends: HashSet<Vec<u64>>,
+    moves: Vec<Match>,
+    nodes: usize,
+    max_nodes: Option<usize>,
+    deadline: Option<Instant>,
+    aborted: bool,
+}
+
+impl Search {
+    fn search(&mut self) -> bool {
+        if self.tiles.iter().all(|tile| !tile.visible) {
+            return true;
+        }
+
+        self.nodes += 1;
+        if self.max_nodes.map(|max| self.nodes > max).unwrap_or(false)
+            || (self.nodes.is_multiple_of(1024)
+                && self
+                    .deadline
+                    .map(|deadline| Instant::now() >= deadline)
+                    .unwrap_or(false))
+        {
+            self.aborted = true;
+            return false;
+        }
+
+        let key = board::visible_key(&self.tiles);
+        if self.dead_ends.contains(&key) {
+            return false;
+        }
+
+        if self.has_deadlock() {
+            self.dead_ends.insert(key);
+            return false;
+        }
+
+        let matches = self.find_moves();
+
+        for m in matches {
+            self.exposure.set_visible(&mut self.tiles, m.0, false);
+            self.exposure.set_visible(&mut self.tiles, m.1, false);
+            self.moves.push(m);
+
+            if self.search() {
+                return true;
+            }
+
+            self.moves.pop();
+            self.exposure.set_visible(&mut self.tiles, m.0, true);
+            self.exposure.set_visible(&mut self.tiles, m.1, true);
+
+            if self.aborted {
+                return false;
+            }
+        }
+
+        self.dead_ends.insert(key);
+        false
+    }
+
+    /// Finds candidate moves, most promising first
+    fn find_moves(&self) -> Vec<Match> {
+        let exposed = self.exposure.exposed(&self.tiles);
+        let mut moves = board::find_all_matches(&self.tiles, &self.exposure);
+
+        // removing all the remaining tiles of a kind can never block a solution,
+        // so there is no need to try anything else
+        if let Some(m) = moves.iter().find(|m| {
+            let tile = &self.tiles[m.0];
+            let exposed_count = exposed
+                .iter()
+                .filter(|i| self.tiles[**i].matches(tile))
+                .count();
+            exposed_count == self.count_visible_of_kind(tile)
+        }) {
+            return vec![*m];
+        }
+
+        // prefer removing tiles on upper layers and tiles that block many others
+        moves.sort_by_cached_key(|m| {
+            std::cmp::Reverse(
+                self.count_blocked(m.0)
+                    + self.count_blocked(m.1)
+                    + 10 * (self.tiles[m.0].slot.z + self.tiles[m.1].slot.z) as usize,
+            )
+        });
+        moves
+    }
+
+    /// Returns true if the last two tiles of some kind are stacked on top of each other
+    fn has_deadlock(&self) -> bool {
+        let mut kinds: HashMap<usize, Vec<&Slot>> = HashMap::new();
+        for tile in self.tiles.iter().filter(|tile| tile.visible) {
+            kinds.entry(tile.id / 4).or_default().push(&tile.slot);
+        }
+
+        kinds.values().any(|slots| match slots[..] {
+            [a, b] => covers(a, b) || covers(b, a),
+            _ => false,
+        })
+    }
+
+    fn count_visible_of_kind(&self, tile: &Tile) -> usize {
+        self.tiles
+            .iter()
+            .filter(|other| other.visible && other.matches(tile))
+            .count()
+    }
+
+    /// Counts visible tiles lying under or beside the given tile
+    fn count_blocked(&self, index: usize) -> usize {
+        let neighbors = self.exposure.neighbors();
+        neighbors
+            .below(index)
+            .iter()
+            .chain(neighbors.beside(index))
+            .filter(|&&i| self.tiles[i].visible)
+            .count()
+    }
+}
+
+fn covers(upper: &Slot, lower: &Slot) -> bool {
+    upper.z == lower.z + 1
+        && upper.x >= lower.x - 1
+        && upper.x <= lower.x + 1
+        && upper.y >= lower.y - 1
+        && upper.y <= lower.y + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map;
+
+    fn tile(id: usize, x: isize, z: isize) -> Tile {
+        Tile {
+            id,
+            slot: Slot { x, y: 0, z },
+            visible: true,
+        }
+    }
+
+    #[test]
+    fn solution_clears_board_with_legal_moves() {
+        let mut board = Board::new(map::default::EASY.clone(), 3).unwrap();
+        let moves = match Solver::new().solve(&board) {
+            Solution::Solved(moves) => moves,
+            solution => panic!("{:?}", solution),
+        };
+
+        for m in moves {
+            assert!(board.can_remove(m), "{:?}", m);
+            board.remove(m);
+        }
+        assert!(board.is_cleared());
+    }
+
+    #[test]
+    fn last_pair_stacked_is_unsolvable() {
+        let tiles = vec![tile(0, 0, 0), tile(1, 0, 1), tile(4, 4, 0), tile(5, 8, 0)];
+        let neighbors = Arc::new(Neighbors::new(&tiles));
+
+        assert_eq!(
+            Solver::new().solve_tiles(&tiles, &neighbors),
+            Solution::Unsolvable
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::rating::Rating;
+use crate::score::Score;
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Version of statistics file format, incremented on incompatible changes
+const VERSION: u64 = 1;
+
+/// How a game ended
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Won,
+    /// Given up with no moves left
+    Stuck,
+    /// Given up with moves still available
+    Abandoned,
+}
+
+/// Lifetime statistics of a map
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapStats {
+    pub started: u64,
+    pub won: u64,
+    pub stuck: u64,
+    pub abandoned: u64,
+    /// Sum of completion times of won games
+    pub total_win_time: Duration,
+    pub hints: u64,
+    pub undos: u64,
+    pub shuffles: u64,
+    pub current_streak: u64,
+    pub best_streak: u64,
+    /// Results of rated games by the band of their rating
+    #[serde(default)]
+    pub by_rating: BTreeMap<u32, RatingStats>,
+}
+
+impl MapStats {
+    pub fn average_win_time(&self) -> Option<Duration> {
+        average(self.total_win_time, self.won)
+    }
+}
+
+/// Results of games with comparably hard deals
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatingStats {
+    /// Finished or given up games
+    pub played: u64,
+    pub won: u64,
+    /// Sum of completion times of won games
+    pub total_win_time: Duration,
+}
+
+impl RatingStats {
+    pub fn average_win_time(&self) -> Option<Duration> {
+        average(self.total_win_time, self.won)
+    }
+}
+
+fn average(total: Duration, count: u64) -> Option<Duration> {
+    if count > 0 {
+        Some(total / count as u32)
+    } else {
+        None
+    }
+}
+
+/// Statistics per map name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Statistics {
+    version: u64,
+    maps: BTreeMap<String, MapStats>,
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            maps: BTreeMap::new(),
+        }
+    }
+}
+
+impl Statistics {
+    /// Loads statistics, or returns empty ones if the file does not exist
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        storage::read_versioned_or_default(path, VERSION)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        storage::write(path, self)
+    }
+
+    pub fn get(&self, map_name: &str) -> Option<&MapStats> {
+        self.maps.get(map_name)
+    }
+
+    /// Returns statistics of all maps in alphabetical order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MapStats)> {
+        self.maps.iter().map(|(name, stats)| (name.as_str(), stats))
+    }
+
+    pub fn record_start(&mut self, map_name: &str) {
+        self.entry(map_name).started += 1;
+    }
+
+    pub fn record_end(
+        &mut self,
+        map_name: &str,
+        outcome: Outcome,
+        score: &Score,
+        time: Duration,
+        rating: Option<&Rating>,
+    ) {
+        let stats = self.entry(map_name);
+
+        if let Some(rating) = rating {
+            let rating_stats = stats.by_rating.entry(rating.band()).or_default();
+            rating_stats.played += 1;
+            if outcome == Outcome::Won {
+                rating_stats.won += 1;
+                rating_stats.total_win_time += time;
+            }
+        }
+
+        match outcome {
+            Outcome::Won => {
+                stats.won += 1;
+                stats.total_win_time += time;
+                stats.current_streak += 1;
+                stats.best_streak = stats.best_streak.max(stats.current_streak);
+            }
+            Outcome::Stuck => {
+                stats.stuck += 1;
+                stats.current_streak = 0;
+            }
+            Outcome::Abandoned => {
+                stats.abandoned += 1;
+                stats.current_streak = 0;
+            }
+        }
+
+        stats.hints += score.hints as u64;
+        stats.undos += score.undos as u64;
+        stats.shuffles += score.shuffles as u64;
+    }
+
+    fn entry(&mut self, map_name: &str) -> &mut MapStats {
+        self.maps.entry(map_name.to_string()).or_default()
+    }
+}
+
+pub fn file() -> Option<PathBuf> {
+    crate::data_dir().map(|dir| dir.join("stats.json"))
+}
+
+/// Loads, updates and saves statistics
+pub fn update<F: FnOnce(&mut Statistics)>(f: F) -> Result<()> {
+    let file = file().ok_or_else(|| anyhow::anyhow!("No data directory"))?;
+    let mut stats = Statistics::load(&file)?;
+    f(&mut stats);
+    stats.save(file)
+}
@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// Reads a JSON file whose top-level `version` field has to match the given one
+pub(crate) fn read_versioned<T: DeserializeOwned, P: AsRef<Path>>(
+    path: P,
+    version: u64,
+) -> Result<T> {
+    let reader = BufReader::new(File::open(path)?);
+    let value: serde_json::Value = serde_json::from_reader(reader)?;
+
+    let file_version = value
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .ok_or_else(|| anyhow!("Invalid file: no version"))?;
+    if file_version != version {
+        return Err(anyhow!("Unsupported version"));
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Like `read_versioned`, but returns the default value if the file does not exist
+pub(crate) fn read_versioned_or_default<T, P>(path: P, version: u64) -> Result<T>
+where
+    T: DeserializeOwned + Default,
+    P: AsRef<Path>,
+{
+    if path.as_ref().exists() {
+        read_versioned(path, version)
+    } else {
+        Ok(T::default())
+    }
+}
+
+/// Writes a JSON file, creating parent directories as needed
+pub(crate) fn write<T: Serialize, P: AsRef<Path>>(path: P, value: &T) -> Result<()> {
+    if let Some(parent) = path.as_ref().parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, value)?;
+
+    Ok(())
+}
@@ -0,0 +1,430 @@
+use anyhow::{anyhow, Result};
+use piston_window::*;
+use std::path::Path;
+
+/// Number of tile images in a row of themes
+pub const COLUMNS: u32 = 43;
+/// Rows of themes, normal tiles above highlighted ones
+pub const ROWS: u32 = 2;
+
+/// Largest width and height of the texture, as a texture cannot be arbitrarily large
+const MAX_TEXTURE_SIZE: u32 = 8192;
+/// Number of rasterised sizes kept for switching back quickly
+const CACHED_SIZES: usize = 4;
+
+/// Tile images in the layout of GNOME Mahjongg themes, whatever format they were loaded from
+pub struct Tileset {
+    texture: G2dTexture,
+    /// Height of the tile face divided by its width
+    face_aspect: f64,
+    /// Offset of tiles on the layer above, relative to the face size
+    level_offset: [f64; 2],
+    source: Source,
+    /// Number of tile images in a row of the texture
+    ///
+    /// Large tiles are wrapped into several bands of `ROWS` rows to keep the texture narrow enough.
+    columns: u32,
+    /// Tile image size requested last and the texture rendered for it
+    size: Option<[u32; 2]>,
+    /// Textures rendered for other sizes, oldest first
+    cache: Vec<([u32; 2], G2dTexture)>,
+}
+
+/// What tiles are rendered from
+enum Source {
+    /// Raster image, which cannot be re-rendered
+    Image,
+    GnomeMahjongg(usvg::Tree),
+    KMahjongg(usvg::Tree, Metrics),
+}
+
+impl Tileset {
+    /// Loads a GNOME Mahjongg theme (SVG or raster image) or a KMahjongg tileset (.desktop)
+    pub fn load<P: AsRef<Path>>(window: &mut PistonWindow, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let is_desktop = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("desktop"))
+            .unwrap_or(false);
+
+        if is_desktop {
+            load_kmahjongg(window, path)
+        } else {
+            load_gnome_mahjongg(window, path)
+        }
+    }
+
+    pub fn texture(&self) -> &G2dTexture {
+        &self.texture
+    }
+
+    /// Returns the size of a tile image in the texture
+    pub fn image_size(&self) -> [f64; 2] {
+        let (width, height) = self.texture.get_size();
+        [
+            width as f64 / self.columns as f64,
+            height as f64 / (ROWS * bands(self.columns)) as f64,
+        ]
+    }
+
+    /// Returns the column and row of the image of a tile in the texture
+    pub fn image_position(&self, id: usize, highlighted: bool) -> [u32; 2] {
+        let index = image_index(id) as u32;
+        [
+            index % self.columns,
+            index / self.columns * ROWS + highlighted as u32,
+        ]
+    }
+
+    pub fn face_aspect(&self) -> f64 {
+        self.face_aspect
+    }
+
+    pub fn level_offset(&self) -> [f64; 2] {
+        self.level_offset
+    }
+
+    /// Returns true if tiles can be rendered at any size
+    pub fn is_scalable(&self) -> bool {
+        !matches!(self.source, Source::Image)
+    }
+
+    /// Re-renders tiles so that a tile image in the texture has the given size in pixels
+    pub fn render_at(&mut self, window: &mut PistonWindow, size: [u32; 2]) -> Result<()> {
+        let size = fit_texture([size[0].max(1), size[1].max(1)]);
+        if !self.is_scalable() || self.size == Some(size) {
+            return Ok(());
+        }
+
+        let texture = if let Some(i) = self.cache.iter().position(|(cached, _)| *cached == size) {
+            self.cache.remove(i).1
+        } else {
+            let strip = match &self.source {
+                Source::Image => unreachable!(),
+                Source::GnomeMahjongg(tree) => {
+                    // aspect ratio of the SVG is kept, the difference is small enough to scale
+                    render_svg_tree(tree, usvg::FitTo::Width(size[0] * COLUMNS))?
+                }
+                Source::KMahjongg(tree, metrics) => {
+                    render_kmahjongg_tiles(tree, &metrics.scaled(size))?
+                }
+            };
+            let buf = wrap_columns(strip, texture_columns(size[0]));
+            Texture::from_image(
+                &mut window.create_texture_context(),
+                &buf,
+                &TextureSettings::new(),
+            )
+            .map_err(|_| anyhow!("Failed to load texture"))?
+        };
+
+        let old = std::mem::replace(&mut self.texture, texture);
+        self.columns = texture_columns(size[0]);
+        if let Some(old_size) = self.size.replace(size) {
+            self.cache.push((old_size, old));
+            if self.cache.len() > CACHED_SIZES {
+                self.cache.remove(0);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the number of tile images in a row of a texture for images of the given width
+fn texture_columns(width: u32) -> u32 {
+    (MAX_TEXTURE_SIZE / width).clamp(1, COLUMNS)
+}
+
+/// Returns the number of bands of `ROWS` rows needed for all the images
+fn bands(columns: u32) -> u32 {
+    COLUMNS.div_ceil(columns)
+}
+
+/// Shrinks a tile image size, keeping its aspect ratio, until the texture fits the size limit
+fn fit_texture(size: [u32; 2]) -> [u32; 2] {
+    let mut size = [size[0].min(MAX_TEXTURE_SIZE), size[1]];
+    while size[1] * ROWS * bands(texture_columns(size[0])) > MAX_TEXTURE_SIZE {
+        size = [(size[0] * 9 / 10).max(1), (size[1] * 9 / 10).max(1)];
+    }
+    size
+}
+
+/// Moves the images of a single band into bands of the given number of columns
+fn wrap_columns(strip: ::image::RgbaImage, columns: u32) -> ::image::RgbaImage {
+    if columns >= COLUMNS {
+        return strip;
+    }
+
+    let width = strip.width() / COLUMNS;
+    let height = strip.height();
+    let mut buf = ::image::RgbaImage::new(width * columns, height * bands(columns));
+    for column in 0..COLUMNS {
+        let image = ::image::imageops::crop_imm(&strip, column * width, 0, width, height);
+        ::image::imageops::replace(
+            &mut buf,
+            &image,
+            column % columns * width,
+            column / columns * height,
+        );
+    }
+    buf
+}
+
+/// Returns the column of the image for a tile id
+pub fn image_index(id: usize) -> usize {
+    let set = id / 4;
+
+    // Invalid ids
+    if set >= 36 {
+        unreachable!()
+    }
+
+    /* The bonus tiles have different images for each */
+    if set == 33 {
+        33 + id % 4
+    } else if set == 35 {
+        38 + id % 4
+    } else if set == 34 {
+        /* The white dragons are inbetween the bonus tiles just to be confusing */
+        37
+    } else {
+        /* Everything else is in set order */
+        set
+    }
+}
+
+fn load_gnome_mahjongg(window: &mut PistonWindow, path: &Path) -> Result<Tileset> {
+    let (texture, source) = if let Ok(tree) = load_svg(path) {
+        let buf = render_svg_tree(&tree, usvg::FitTo::Original)?;
+        let texture = Texture::from_image(
+            &mut window.create_texture_context(),
+            &buf,
+            &TextureSettings::new(),
+        )
+        .map_err(|_| anyhow!("Failed to load texture"))?;
+        (texture, Source::GnomeMahjongg(tree))
+    } else {
+        let texture = Texture::from_path(
+            &mut window.create_texture_context(),
+            path,
+            Flip::None,
+            &TextureSettings::new(),
+        )
+        .map_err(|_| anyhow!("Failed to load texture"))?;
+        (texture, Source::Image)
+    };
+
+    let (width, height) = texture.get_size();
+    Ok(Tileset {
+        texture,
+        face_aspect: (height as f64 / ROWS as f64) / (width as f64 / COLUMNS as f64),
+        level_offset: [1.0 / 7.0, 1.0 / 10.0],
+        source,
+        columns: COLUMNS,
+        size: None,
+        cache: Vec::new(),
+    })
+}
+
+fn load_svg<P: AsRef<Path>>(path: P) -> Result<usvg::Tree> {
+    let data = std::fs::read(path)?;
+    Ok(usvg::Tree::from_data(
+        &data,
+        &usvg::Options::default().to_ref(),
+    )?)
+}
+
+pub fn render_svg<P: AsRef<Path>>(path: P) -> Result<::image::RgbaImage> {
+    render_svg_tree(&load_svg(path)?, usvg::FitTo::Original)
+}
+
+fn render_svg_tree(tree: &usvg::Tree, fit_to: usvg::FitTo) -> Result<::image::RgbaImage> {
+    let size = fit_to
+        .fit_to(tree.svg_node().size.to_screen_size())
+        .ok_or_else(|| anyhow!("Invalid size"))?;
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or_else(|| anyhow!("Failed to create pixmap"))?;
+
+    resvg::render(tree, fit_to, pixmap.as_mut()).ok_or_else(|| anyhow!("Failed to render SVG"))?;
+
+    let buf = ::image::RgbaImage::from_vec(pixmap.width(), pixmap.height(), pixmap.take())
+        .ok_or_else(|| anyhow!("Failed to construct image buffer from rendered SVG"))?;
+
+    Ok(buf)
+}
+
+/// Sizes of KMahjongg tiles in pixels
+struct Metrics {
+    tile: [u32; 2],
+    face: [u32; 2],
+    level_offset: [u32; 2],
+}
+
+impl Metrics {
+    /// Returns the metrics scaled to the given tile size
+    fn scaled(&self, tile: [u32; 2]) -> Self {
+        let scale = |value: u32, i: usize| {
+            ((value as f64 * tile[i] as f64 / self.tile[i] as f64).round() as u32).max(1)
+        };
+        Self {
+            tile,
+            face: [scale(self.face[0], 0), scale(self.face[1], 1)],
+            level_offset: [
+                scale(self.level_offset[0], 0),
+                scale(self.level_offset[1], 1),
+            ],
+        }
+    }
+}
+
+fn load_kmahjongg(window: &mut PistonWindow, path: &Path) -> Result<Tileset> {
+    const TILESET_VERSION_FORMAT: i32 = 1;
+
+    let desktop = ini::Ini::load_from_file(path)?;
+    let section = desktop
+        .section(Some("KMahjonggTileset"))
+        .ok_or_else(|| anyhow!("Invalid tileset format"))?;
+
+    if let Some(version) = section.get("VersionFormat") {
+        if version.parse::<i32>().unwrap_or(0) > TILESET_VERSION_FORMAT {
+            return Err(anyhow!("Unsupported version"));
+        }
+    }
+
+    let filename = section
+        .get("FileName")
+        .ok_or_else(|| anyhow!("Invalid tileset: no SVG filename"))?;
+    // safe to unwrap, or reading desktop file would have failed
+    let filename = path.parent().unwrap().join(filename);
+
+    // defaults are those of KMahjongg
+    let get = |key: &str, default: u32| {
+        section
+            .get(key)
+            .and_then(|value| value.parse().ok())
+            .filter(|&value| value > 0)
+            .unwrap_or(default)
+    };
+    let metrics = Metrics {
+        tile: [get("TileWidth", 30), get("TileHeight", 50)],
+        face: [get("TileFaceWidth", 30), get("TileFaceHeight", 50)],
+        level_offset: [get("LevelOffsetX", 10), get("LevelOffsetY", 10)],
+    };
+
+    let tree = load_svg(filename)?;
+    let buf = render_kmahjongg_tiles(&tree, &metrics)?;
+
+    let texture = Texture::from_image(
+        &mut window.create_texture_context(),
+        &buf,
+        &TextureSettings::new(),
+    )
+    .map_err(|_| anyhow!("Failed to load texture"))?;
+
+    Ok(Tileset {
+        texture,
+        face_aspect: metrics.face[1] as f64 / metrics.face[0] as f64,
+        level_offset: [
+            metrics.level_offset[0] as f64 / metrics.face[0] as f64,
+            metrics.level_offset[1] as f64 / metrics.face[1] as f64,
+        ],
+        source: Source::KMahjongg(tree, metrics),
+        columns: COLUMNS,
+        size: None,
+        cache: Vec::new(),
+    })
+}
+
+/// Composes the tiles of a KMahjongg tileset into the layout of GNOME Mahjongg themes
+fn render_kmahjongg_tiles(tree: &usvg::Tree, metrics: &Metrics) -> Result<::image::RgbaImage> {
+    let [width, height] = metrics.tile;
+    let mut pixmap = tiny_skia::Pixmap::new(width * COLUMNS, height * ROWS)
+        .ok_or_else(|| anyhow!("Failed to create pixmap"))?;
+
+    let faces = (0..COLUMNS)
+        .map(|column| {
+            face_element_id(column)
+                .map(|id| render_element(tree, &id, metrics.face))
+                .transpose()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (row, tile_id) in ["TILE_1", "TILE_1_SEL"].iter().enumerate() {
+        let tile = render_element(tree, tile_id, metrics.tile)?;
+
+        for (column, face) in (0..COLUMNS).zip(&faces) {
+            let x = (column * width) as i32;
+            let y = (row as u32 * height) as i32;
+            pixmap
+                .draw_pixmap(
+                    x,
+                    y,
+                    tile.as_ref(),
+                    &tiny_skia::PixmapPaint::default(),
+                    tiny_skia::Transform::identity(),
+                    None,
+                )
+                .ok_or_else(|| anyhow!("Failed to compose tile"))?;
+
+            let face = if let Some(face) = face {
+                face
+            } else {
+                continue;
+            };
+            // the face is at the top right, with the edges on the left and bottom
+            pixmap
+                .draw_pixmap(
+                    x + (width - metrics.face[0].min(width)) as i32,
+                    y,
+                    face.as_ref(),
+                    &tiny_skia::PixmapPaint::default(),
+                    tiny_skia::Transform::identity(),
+                    None,
+                )
+                .ok_or_else(|| anyhow!("Failed to compose tile"))?;
+        }
+    }
+
+    let buf = ::image::RgbaImage::from_vec(pixmap.width(), pixmap.height(), pixmap.take())
+        .ok_or_else(|| anyhow!("Failed to construct image buffer from rendered SVG"))?;
+
+    Ok(buf)
+}
+
+/// Renders an SVG element to fit the given size
+fn render_element(tree: &usvg::Tree, id: &str, size: [u32; 2]) -> Result<tiny_skia::Pixmap> {
+    let node = tree
+        .node_by_id(id)
+        .ok_or_else(|| anyhow!("Invalid tileset: no element {}", id))?;
+
+    let mut pixmap = tiny_skia::Pixmap::new(size[0], size[1])
+        .ok_or_else(|| anyhow!("Failed to create pixmap"))?;
+    resvg::render_node(
+        tree,
+        &node,
+        usvg::FitTo::Size(size[0], size[1]),
+        pixmap.as_mut(),
+    )
+    .ok_or_else(|| anyhow!("Failed to render {}", id))?;
+
+    Ok(pixmap)
+}
+
+/// Returns the id of the KMahjongg element for a column of GNOME Mahjongg themes
+fn face_element_id(column: u32) -> Option<String> {
+    let id = match column {
+        0..=8 => format!("ROD_{}", column + 1),
+        9..=17 => format!("BAMBOO_{}", column - 8),
+        18..=26 => format!("CHARACTER_{}", column - 17),
+        27..=30 => format!("WIND_{}", column - 26),
+        31..=32 => format!("DRAGON_{}", column - 30),
+        33..=36 => format!("SEASON_{}", column - 32),
+        37 => "DRAGON_3".to_string(),
+        38..=41 => format!("FLOWER_{}", column - 37),
+        _ => return None,
+    };
+
+    Some(id)
+}